@@ -0,0 +1,36 @@
+// Per-call overhead of Callback::call when called from the loop's own thread (local queue),
+// from another thread (mio channel) and of LocalCallback::call.
+// Run with: cargo bench --bench callback
+
+#[macro_use] extern crate noders;
+
+use std::thread;
+use std::time::Instant;
+use noders::node::Loop;
+
+const CALLS: u32 = 1_000_000;
+
+fn report(what: &str, start: Instant) {
+    let elapsed = start.elapsed();
+    println!("{:<14} {:>8.1} ns/call", what, elapsed.as_secs_f64() * 1e9 / CALLS as f64);
+}
+
+fn main() {
+    noders::module().run((), |s| {
+        let start = Instant::now();
+        let cb = s.cb(move |_, i: u32| { if i == CALLS - 1 { report("same thread", start); } });
+        for i in 0..CALLS { cb.call(i); }
+    });
+
+    noders::module().run((), |s| {
+        let start = Instant::now();
+        let cb = s.local_cb(move |_, i: u32| { if i == CALLS - 1 { report("local", start); } });
+        for i in 0..CALLS { cb.call(i); }
+    });
+
+    noders::module().run(rec!{ start: Instant::now() }, |s| {
+        s.start = Instant::now();
+        let cb = s.cb(|s, i: u32| { if i == CALLS - 1 { report("cross thread", s.start); } });
+        thread::spawn(move || { for i in 0..CALLS { cb.call(i); } });
+    });
+}
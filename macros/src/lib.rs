@@ -0,0 +1,249 @@
+//! Procedural macros for noders, use them through the noders crate.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use quote::{ quote, format_ident };
+use syn::parse::{ Parse, ParseStream };
+use syn::punctuated::Punctuated;
+use syn::{ parse_macro_input, Attribute, Expr, Ident, Token, Type, Visibility };
+
+struct Field {
+    name: Ident,
+    ty: Option<Type>,
+    val: Option<Expr>
+}
+impl Parse for Field {
+    // name: expr | name: Type = expr | name: Type
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let fork = input.fork();
+        if fork.parse::<Type>().is_ok() && (fork.peek(Token![=]) || fork.peek(Token![,]) ||
+            fork.is_empty())
+        {
+            let ty = input.parse()?;
+            if !input.peek(Token![=]) { return Ok(Field { name, ty: Some(ty), val: None }); }
+            input.parse::<Token![=]>()?;
+            return Ok(Field { name, ty: Some(ty), val: Some(input.parse()?) });
+        }
+        Ok(Field { name, ty: None, val: Some(input.parse()?) })
+    }
+}
+
+struct Rec {
+    attrs: Vec<Attribute>,
+    // Set for the named form, `pub struct Name { ... }`
+    named: Option<(Visibility, Ident)>,
+    fields: Punctuated<Field, Token![,]>
+}
+impl Parse for Rec {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let fork = input.fork();
+        fork.parse::<Visibility>()?;
+        if !fork.peek(Token![struct]) {
+            let fields = Punctuated::parse_terminated(input)?;
+            return Ok(Rec { attrs, named: None, fields });
+        }
+        let vis = input.parse()?;
+        input.parse::<Token![struct]>()?;
+        let name = input.parse()?;
+        let content;
+        syn::braced!(content in input);
+        let fields = Punctuated::parse_terminated(&content)?;
+        Ok(Rec { attrs, named: Some((vis, name)), fields })
+    }
+}
+
+fn anonymous(r: Rec) -> syn::Result<proc_macro2::TokenStream> {
+    let attrs = &r.attrs;
+    let mut params = Vec::new();
+    let mut decls = Vec::new();
+    let mut inits = Vec::new();
+    for (i, f) in r.fields.iter().enumerate() {
+        let name = &f.name;
+        let (ty, val) = match (f.ty.as_ref(), f.val.as_ref()) {
+            (Some(t), Some(v)) => (quote!(#t), quote!(#v)),
+            // `name: x` or `name: ()` parses as a type, but here it can only be a value
+            (Some(t), None) => match syn::parse2::<Expr>(quote!(#t)) {
+                Ok(v) => {
+                    let p = format_ident!("__T{}", i);
+                    params.push(p.clone());
+                    (quote!(#p), quote!(#v))
+                },
+                Err(_) => { return Err(syn::Error::new(name.span(), "rec! field needs a value")); }
+            },
+            (None, None) => unreachable!(),
+            (None, Some(v)) => {
+                let p = format_ident!("__T{}", i);
+                params.push(p.clone());
+                (quote!(#p), quote!(#v))
+            }
+        };
+        decls.push(quote!(#name: #ty));
+        inits.push(quote!(#name: #val));
+    }
+    Ok(quote!({
+        #(#attrs)*
+        #[allow(non_camel_case_types)]
+        struct Rec<#(#params),*> { #(#decls),* }
+        Rec { #(#inits),* }
+    }))
+}
+
+fn named(r: Rec, vis: Visibility, name: Ident) -> syn::Result<proc_macro2::TokenStream> {
+    let attrs = &r.attrs;
+    let mut decls = Vec::new();
+    let mut inits = Vec::new();
+    for f in r.fields.iter() {
+        let fname = &f.name;
+        let ty = match f.ty.as_ref() {
+            Some(t) => t,
+            None => {
+                return Err(syn::Error::new(fname.span(), "fields of a named rec! need a type"));
+            }
+        };
+        decls.push(quote!(pub #fname: #ty));
+        if let Some(v) = f.val.as_ref() { inits.push(quote!(#fname: #v)); }
+    }
+    // new() only exists if every field has an initial value
+    let new = if inits.len() == decls.len() {
+        quote!(impl #name {
+            #[allow(dead_code)]
+            pub fn new() -> #name { #name { #(#inits),* } }
+        })
+    } else {
+        quote!()
+    };
+    Ok(quote!(
+        #(#attrs)*
+        #vis struct #name { #(#decls),* }
+        #new
+    ))
+}
+
+/// See noders::rec!
+#[proc_macro]
+pub fn record(input: TokenStream) -> TokenStream {
+    let mut r = parse_macro_input!(input as Rec);
+    let out = match r.named.take() {
+        Some((vis, name)) => named(r, vis, name),
+        None => anonymous(r)
+    };
+    out.unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+struct Opts {
+    threads: Option<syn::LitInt>,
+    clock: Option<Ident>,
+    state: Option<Expr>,
+    timeout: Option<syn::LitInt>
+}
+fn opts(args: TokenStream, test: bool) -> syn::Result<Opts> {
+    let mut o = Opts { threads: None, clock: None, state: None, timeout: None };
+    let parser = Punctuated::<syn::MetaNameValue, Token![,]>::parse_terminated;
+    for nv in syn::parse::Parser::parse(parser, args)? {
+        let int = || match &nv.value {
+            Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(i), .. }) => Ok(i.clone()),
+            v => Err(syn::Error::new_spanned(v, "expecting an integer"))
+        };
+        match nv.path.get_ident().map(|i| i.to_string()).as_deref() {
+            Some("threads") => { o.threads = Some(int()?); },
+            Some("clock") => {
+                let clock = match &nv.value {
+                    Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(s), .. }) => match &*s.value() {
+                        "system" => Some(Ident::new("System", s.span())),
+                        "virtual" => Some(Ident::new("Virtual", s.span())),
+                        _ => None
+                    },
+                    _ => None
+                };
+                if clock.is_none() {
+                    return Err(syn::Error::new_spanned(&nv.value,
+                        "expecting \"system\" or \"virtual\""));
+                }
+                o.clock = clock;
+            },
+            Some("state") => { o.state = Some(nv.value.clone()); },
+            Some("timeout") if test => { o.timeout = Some(int()?); },
+            _ => {
+                let known = if test { "threads, clock, state or timeout" } else {
+                    "threads, clock or state"
+                };
+                return Err(syn::Error::new_spanned(&nv.path, format!("expecting {}", known)));
+            }
+        }
+    }
+    Ok(o)
+}
+
+// The function's body as a closure over its scope argument, if it has one
+fn body(f: &syn::ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let block = &f.block;
+    let ret = match &f.sig.output {
+        syn::ReturnType::Default => quote!(),
+        syn::ReturnType::Type(_, t) => quote!(-> #t)
+    };
+    let mut inputs = f.sig.inputs.iter();
+    let arg = match inputs.next() {
+        Some(syn::FnArg::Typed(pt)) => quote!(#pt),
+        Some(a) => { return Err(syn::Error::new_spanned(a, "expecting a scope argument")); }
+        None => quote!(_)
+    };
+    if let Some(a) = inputs.next() {
+        return Err(syn::Error::new_spanned(a, "only the scope is passed"));
+    }
+    Ok(quote!(move |#arg| #ret #block))
+}
+
+fn module(o: &Opts) -> proc_macro2::TokenStream {
+    let mut m = quote!(::noders::module());
+    if let Some(n) = o.threads.as_ref() { m = quote!(#m.threadpool_size(#n)); }
+    if let Some(c) = o.clock.as_ref() { m = quote!(#m.clock(::noders::node::Clock::#c)); }
+    m
+}
+
+/// Run the function as the body of a loop, it gets the loop's scope as its argument.
+/// `#[noders::main(threads = 8, clock = "virtual", state = rec!{ n: 0 })]` sets the threadpool
+/// size, the clock (see noders::node::Clock, default "system") and the scope state (default
+/// `()`).
+#[proc_macro_attribute]
+pub fn main(args: TokenStream, item: TokenStream) -> TokenStream {
+    let f = parse_macro_input!(item as syn::ItemFn);
+    let out = opts(args, false).and_then(|o| {
+        let body = body(&f)?;
+        let (attrs, vis, name, output) = (&f.attrs, &f.vis, &f.sig.ident, &f.sig.output);
+        let m = module(&o);
+        let state = o.state.map(|s| quote!(#s)).unwrap_or(quote!(()));
+        Ok(quote!(
+            #(#attrs)*
+            #vis fn #name() #output { #m.run(#state, #body) }
+        ))
+    });
+    out.unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+/// A test whose body runs in a loop on its own thread, it fails if the loop hasn't gone idle
+/// after `timeout` ms (default 5000). Takes the same options as main.
+#[proc_macro_attribute]
+pub fn test(args: TokenStream, item: TokenStream) -> TokenStream {
+    let f = parse_macro_input!(item as syn::ItemFn);
+    let out = opts(args, true).and_then(|o| {
+        if let syn::ReturnType::Type(_, t) = &f.sig.output {
+            return Err(syn::Error::new_spanned(t, "a loop test doesn't return anything"));
+        }
+        let body = body(&f)?;
+        let (attrs, name) = (&f.attrs, &f.sig.ident);
+        let m = module(&o);
+        let state = o.state.as_ref().map(|s| quote!(#s)).unwrap_or(quote!(()));
+        let timeout = o.timeout.as_ref().map(|t| quote!(#t)).unwrap_or(quote!(5000));
+        Ok(quote!(
+            #[test]
+            #(#attrs)*
+            fn #name() {
+                if let Err(e) = #m.run_timeout(#state, #timeout, #body) { panic!("{}", e); }
+            }
+        ))
+    });
+    out.unwrap_or_else(|e| e.to_compile_error()).into()
+}
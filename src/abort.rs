@@ -0,0 +1,131 @@
+use std::rc::{ Rc, Weak };
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use callback::Callback;
+use node::Loop;
+use super::Token;
+
+/// The error which an operation is failed with when its AbortSignal fires
+#[derive(Debug)]
+pub struct Aborted;
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "operation aborted") }
+}
+impl Error for Aborted {}
+
+pub fn aborted() -> io::Error { io::Error::other(Aborted) }
+pub fn is_aborted(e: &io::Error) -> bool {
+    e.get_ref().map(|e| e.is::<Aborted>()).unwrap_or(false)
+}
+
+struct SignalPvt {
+    aborted: bool,
+    next_id: usize,
+    on_abort: BTreeMap<usize, Box<dyn FnOnce()>>
+}
+
+/// Tells the operations which it is given to that they should be abandoned, see
+/// AbortController.
+#[derive(Clone)]
+pub struct AbortSignal {
+    s: Rc<RefCell<SignalPvt>>
+}
+impl AbortSignal {
+    fn new() -> AbortSignal {
+        AbortSignal { s: Rc::new(RefCell::new(SignalPvt {
+            aborted: false,
+            next_id: 0,
+            on_abort: BTreeMap::new()
+        })) }
+    }
+    pub fn aborted(&self) -> bool { self.s.borrow().aborted }
+    /// Call f when the signal fires, or now if it already has. Returns an id for forget().
+    pub fn on_abort<F:'static+FnOnce()>(&self, f:F) -> usize {
+        let mut s = self.s.borrow_mut();
+        let id = s.next_id;
+        s.next_id += 1;
+        if s.aborted { drop(s); f(); return id; }
+        s.on_abort.insert(id, Box::new(f));
+        id
+    }
+    pub fn forget(&self, id: usize) {
+        let f = self.s.borrow_mut().on_abort.remove(&id);
+        drop(f);
+    }
+    // Number of handlers waiting for the signal
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize { self.s.borrow().on_abort.len() }
+    /// A signal which fires after millis. Like Node's, its timer doesn't keep the loop alive.
+    pub fn timeout<L:Loop<L>>(l:&L, millis: u64) -> AbortSignal {
+        let ac = AbortController::new();
+        let sig = ac.signal();
+        let cb = Callback::new_unref(l.core(), ac, |ac, _: Token|{ ac.abort() });
+        let t = l.core().set_timeout(cb, millis, false);
+        l.core().unref_timer(&t);
+        sig
+    }
+    /// A signal which fires as soon as any of these does, it is then removed from all of them.
+    pub fn any(signals: &[&AbortSignal]) -> AbortSignal {
+        let ac = AbortController::new();
+        let mut ids: Vec<(Weak<RefCell<SignalPvt>>, usize)> = Vec::new();
+        for s in signals {
+            let ac = ac.clone();
+            ids.push((Rc::downgrade(&s.s), s.on_abort(move ||{ ac.abort() })));
+        }
+        ac.signal().on_abort(move ||{
+            for (s, id) in ids {
+                if let Some(s) = s.upgrade() { AbortSignal { s }.forget(id); }
+            }
+        });
+        ac.signal()
+    }
+}
+
+#[derive(Clone)]
+pub struct AbortController {
+    signal: AbortSignal
+}
+impl AbortController {
+    pub fn new() -> AbortController { AbortController { signal: AbortSignal::new() } }
+    pub fn signal(&self) -> AbortSignal { self.signal.clone() }
+    /// Fire the signal, the operations which have it fail with an Aborted error.
+    pub fn abort(&self) {
+        let handlers = {
+            let mut s = self.signal.s.borrow_mut();
+            if s.aborted { return; }
+            s.aborted = true;
+            ::std::mem::take(&mut s.on_abort)
+        };
+        for (_, f) in handlers { f(); }
+    }
+}
+impl Default for AbortController {
+    fn default() -> Self { Self::new() }
+}
+
+/// Wrap a completion callback so that it is called with an Aborted error (on the next turn of
+/// the loop) if the signal fires first. Whichever happens first wins, the other is ignored.
+pub fn abortable<L,T,F>(l:&L, signal: &AbortSignal, f:F) -> impl FnOnce(&mut L, io::Result<T>)
+    where
+    L: Loop<L>,
+    T: 'static,
+    F: 'static + FnOnce(&mut L, io::Result<T>)
+{
+    let slot = Rc::new(RefCell::new(Some(f)));
+    let slot_ = slot.clone();
+    let cb = l.cb_once(move |l, ()|{
+        let f = slot_.borrow_mut().take();
+        if let Some(f) = f { f(l, Err(aborted())); }
+    });
+    let id = signal.on_abort(move ||{ cb.call_once(()) });
+    let signal = signal.clone();
+    move |l, res|{
+        signal.forget(id);
+        let f = slot.borrow_mut().take();
+        if let Some(f) = f { f(l, res); }
+    }
+}
@@ -0,0 +1,146 @@
+use std::any::Any;
+use std::cell::{ Cell, RefCell };
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::mem;
+use std::rc::Rc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+type Stores = Rc<BTreeMap<usize, Rc<dyn Any>>>;
+
+// What a callback was created in, it is restored whenever the callback runs.
+#[derive(Clone, Default)]
+pub(crate) struct AsyncCx {
+    id: u64,
+    trigger: u64,
+    stores: Option<Stores>
+}
+
+/// Called as callbacks are made, run and freed by the loops on the thread which created the
+/// hook. Ids are unique per thread, 1 is the code which isn't running in a callback.
+pub trait AsyncHook {
+    /// A callback was made while trigger_id was running
+    fn init(&self, _id: u64, _trigger_id: u64) {}
+    fn before(&self, _id: u64) {}
+    fn after(&self, _id: u64) {}
+    fn destroy(&self, _id: u64) {}
+}
+
+thread_local! {
+    static CURRENT: RefCell<AsyncCx> = RefCell::new(AsyncCx { id: 1, trigger: 0, stores: None });
+    static NEXT_ID: Cell<u64> = const { Cell::new(2) };
+    static HOOKS: RefCell<Vec<(usize, Rc<dyn AsyncHook>)>> = const { RefCell::new(Vec::new()) };
+}
+static NEXT_KEY: AtomicUsize = AtomicUsize::new(0);
+
+fn each_hook<F:Fn(&dyn AsyncHook)>(f:F) {
+    // Copied out so that a hook may make callbacks or hooks of its own
+    let hooks = HOOKS.try_with(|h| {
+        let h = h.borrow();
+        if h.is_empty() { None } else { Some(h.iter().map(|x| x.1.clone()).collect::<Vec<_>>()) }
+    });
+    if let Ok(Some(hooks)) = hooks { for h in hooks { f(&*h); } }
+}
+
+pub(crate) fn init() -> AsyncCx {
+    let id = NEXT_ID.with(|n| { n.set(n.get() + 1); n.get() - 1 });
+    let (trigger, stores) = CURRENT.with(|c| { let c = c.borrow(); (c.id, c.stores.clone()) });
+    each_hook(|h| h.init(id, trigger));
+    AsyncCx { id, trigger, stores }
+}
+// Puts back what was running before when it is dropped, even if the callback panics
+pub(crate) struct Entered(AsyncCx);
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let prev = mem::take(&mut self.0);
+        let id = CURRENT.try_with(|c| c.borrow().id);
+        if let Ok(id) = id { each_hook(|h| h.after(id)); }
+        let _ = CURRENT.try_with(|c| *c.borrow_mut() = prev);
+    }
+}
+pub(crate) fn enter(cx: &AsyncCx) -> Entered {
+    let prev = CURRENT.with(|c| mem::replace(&mut *c.borrow_mut(), cx.clone()));
+    each_hook(|h| h.before(cx.id));
+    Entered(prev)
+}
+pub(crate) fn destroy(cx: &AsyncCx) {
+    if cx.id != 0 { each_hook(|h| h.destroy(cx.id)); }
+}
+
+/// Disables the hook when it is dropped
+pub struct HookHandle {
+    key: usize,
+    // The hook belongs to this thread
+    _rc: PhantomData<Rc<()>>
+}
+impl Drop for HookHandle {
+    fn drop(&mut self) {
+        let key = self.key;
+        let _ = HOOKS.try_with(|h| h.borrow_mut().retain(|x| x.0 != key));
+    }
+}
+
+pub fn create_hook<H:'static + AsyncHook>(h:H) -> HookHandle {
+    let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+    HOOKS.with(|hooks| hooks.borrow_mut().push((key, Rc::new(h))));
+    HookHandle { key, _rc: PhantomData }
+}
+
+/// The id of the callback which is running now
+pub fn execution_async_id() -> u64 { CURRENT.with(|c| c.borrow().id) }
+/// The id of what was running when the current callback was made
+pub fn trigger_async_id() -> u64 { CURRENT.with(|c| c.borrow().trigger) }
+
+struct Restore(Option<Stores>);
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let stores = self.0.take();
+        let _ = CURRENT.try_with(|c| c.borrow_mut().stores = stores);
+    }
+}
+
+/// A value which follows a logical operation through the callbacks it makes, like Node's
+/// AsyncLocalStorage. Callbacks see the store which was set when they were made, wherever they
+/// are called from. Stores are not Send so they don't cross threads: a worker's loop and the
+/// thread pool's work functions start out with no store, and closures posted to a LoopRemote
+/// see the store of wherever the remote was made rather than the poster's.
+pub struct AsyncLocalStorage<T> {
+    key: usize,
+    _t: PhantomData<T>
+}
+impl<T:'static> AsyncLocalStorage<T> {
+    pub fn new() -> AsyncLocalStorage<T> {
+        AsyncLocalStorage { key: NEXT_KEY.fetch_add(1, Ordering::Relaxed), _t: PhantomData }
+    }
+    fn with_store<R,F:FnOnce()->R>(&self, store: Option<Rc<dyn Any>>, f:F) -> R {
+        let prev = CURRENT.with(|c| {
+            let mut c = c.borrow_mut();
+            let mut stores = c.stores.as_ref().map(|s| (**s).clone()).unwrap_or_default();
+            match store {
+                Some(s) => { stores.insert(self.key, s); },
+                None => { stores.remove(&self.key); }
+            }
+            c.stores.replace(Rc::new(stores))
+        });
+        let _restore = Restore(prev);
+        f()
+    }
+    /// Call f with store set, for f and every callback made under it
+    pub fn run<R,F:FnOnce()->R>(&self, store:T, f:F) -> R {
+        self.with_store(Some(Rc::new(store)), f)
+    }
+    /// Call f with no store set
+    pub fn exit<R,F:FnOnce()->R>(&self, f:F) -> R { self.with_store(None, f) }
+    pub fn get(&self) -> Option<Rc<T>> {
+        let s = CURRENT.with(|c| {
+            c.borrow().stores.as_ref().and_then(|s| s.get(&self.key).cloned())
+        });
+        s.and_then(|s| s.downcast::<T>().ok())
+    }
+}
+impl<T:'static> Default for AsyncLocalStorage<T> {
+    fn default() -> Self { Self::new() }
+}
+impl<T> Clone for AsyncLocalStorage<T> {
+    fn clone(&self) -> Self { AsyncLocalStorage { key: self.key, _t: PhantomData } }
+}
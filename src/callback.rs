@@ -1,45 +1,118 @@
 use std::marker::PhantomData;
 use std::sync::Arc;
-use std::sync::Weak;
 use std::any::Any;
+use std::rc::{ self, Rc };
+use std::cell::RefCell;
+use std::collections::{ HashMap, VecDeque };
 use mio_extras::channel::Sender;
 
 use node::Core;
+use async_hooks::{ self, AsyncCx };
 
 
 pub struct CallbackReq {
-    pub x: Box<Any + Send>,
+    pub x: Box<dyn Any + Send>,
     pub canary: Arc<Canary>
 }
 pub enum CallbackEv {
-    Req(CallbackReq)
+    Req(CallbackReq),
+    // The last handle to the callback is gone, the loop may forget it
+    Drop(i32),
+    // Exit the loop now, whatever it is still waiting for
+    Stop,
+    // Nothing to do but wake the loop so it can see whether it's still needed
+    Wake
 }
 
+// A call made from the thread which runs the loop, it skips the channel and so the argument
+// needn't be Send.
+pub struct LocalReq {
+    pub x: Box<dyn Any>,
+    pub canary: Arc<Canary>
+}
+pub enum LocalEv {
+    Req(LocalReq),
+    Drop(i32)
+}
+pub type LocalQueue = Rc<RefCell<VecDeque<LocalEv>>>;
+
+thread_local! {
+    // Local queues of the loops which run on this thread, by loop id
+    static LOCAL_QUEUES: RefCell<HashMap<usize, rc::Weak<RefCell<VecDeque<LocalEv>>>>> =
+        RefCell::new(HashMap::new());
+}
+pub fn register_local_queue(loop_id: usize, q: &LocalQueue) {
+    LOCAL_QUEUES.with(|lq| { lq.borrow_mut().insert(loop_id, Rc::downgrade(q)); });
+}
+pub fn unregister_local_queue(loop_id: usize) {
+    let _ = LOCAL_QUEUES.try_with(|lq| { lq.borrow_mut().remove(&loop_id); });
+}
+fn local_queue(loop_id: usize) -> Option<LocalQueue> {
+    LOCAL_QUEUES.try_with(|lq| lq.borrow().get(&loop_id).and_then(|q| q.upgrade())).unwrap_or(None)
+}
+
+// Shared by a callback handle and every request made with it. A callback is registered with its
+// loop for exactly as long as its canary lives, when the last reference goes the loop is told to
+// drop the callback. Since in-flight requests hold the canary, the drop is always seen after
+// the last call.
 pub struct Canary {
     pub callback_id: i32,
+    sender: Sender<CallbackEv>,
+    loop_id: usize
+}
+impl Drop for Canary {
+    fn drop(&mut self) {
+        if let Some(q) = local_queue(self.loop_id) {
+            q.borrow_mut().push_back(LocalEv::Drop(self.callback_id));
+            return;
+        }
+        // If the loop has already exited there is nobody to tell
+        let _ = self.sender.send(CallbackEv::Drop(self.callback_id));
+    }
 }
 
 
 pub struct CallbackImpl {
-    w:Box<Any>,
-    f:Box<Any>,
-    pub dispatch: fn(&mut CallbackImpl, Box<Any + Send>),
-    pub canary: Weak<Canary>
+    w:Box<dyn Any>,
+    f:Box<dyn Any>,
+    pub dispatch: fn(&mut CallbackImpl, Box<dyn Any>),
+    // Dropped by the loop as soon as it has been called
+    pub once: bool,
+    // If false the loop may exit even though the callback still exists
+    pub keepalive: bool,
+    pub(crate) async_cx: AsyncCx
+}
+impl Drop for CallbackImpl {
+    fn drop(&mut self) { async_hooks::destroy(&self.async_cx); }
 }
-fn dispatch<W,X,F>(cbi: &mut CallbackImpl, mut x: Box<Any + Send>) where
-    F: 'static + Fn(&mut W,X),
+fn dispatch<W,X,F>(cbi: &mut CallbackImpl, mut x: Box<dyn Any>) where
+    F: 'static + FnMut(&mut W,X),
     W: 'static,
     X: 'static
 {
     let w: &mut W = cbi.w.downcast_mut().unwrap();
     let optx: &mut Option<X> = x.downcast_mut().unwrap();
     let x = optx.take().unwrap();
-    let f: &Fn(&mut W,X) = cbi.f.downcast_ref::<F>().unwrap();
+    let f: &mut F = cbi.f.downcast_mut::<F>().unwrap();
     f(w,x);
 }
+fn dispatch_once<W,X,F>(cbi: &mut CallbackImpl, mut x: Box<dyn Any>) where
+    F: 'static + FnOnce(&mut W,X),
+    W: 'static,
+    X: 'static
+{
+    let w: &mut W = cbi.w.downcast_mut().unwrap();
+    let optx: &mut Option<X> = x.downcast_mut().unwrap();
+    let x = optx.take().unwrap();
+    let optf: &mut Option<F> = cbi.f.downcast_mut().unwrap();
+    match optf.take() {
+        Some(f) => f(w,x),
+        None => { warn!("One-shot callback called more than once"); }
+    }
+}
 impl CallbackImpl {
-    fn new<W,X,F>(w:W, f:F, c:&Arc<Canary>) -> CallbackImpl where
-        F: 'static + Fn(&mut W,X),
+    fn new<W,X,F>(w:W, f:F) -> CallbackImpl where
+        F: 'static + FnMut(&mut W,X),
         W: 'static,
         X: 'static
     {
@@ -47,36 +120,115 @@ impl CallbackImpl {
             w:Box::new(w),
             f:Box::new(f),
             dispatch: dispatch::<W,X,F>,
-            canary: Arc::downgrade(c)
+            once: false,
+            keepalive: true,
+            async_cx: AsyncCx::default()
+        }
+    }
+    fn new_once<W,X,F>(w:W, f:F) -> CallbackImpl where
+        F: 'static + FnOnce(&mut W,X),
+        W: 'static,
+        X: 'static
+    {
+        CallbackImpl {
+            w:Box::new(w),
+            f:Box::new(Some(f)),
+            dispatch: dispatch_once::<W,X,F>,
+            once: true,
+            keepalive: true,
+            async_cx: AsyncCx::default()
         }
     }
 }
 
+/// A handle to a function which runs on a loop, it can be called from any thread. The loop
+/// stays alive while any callback handle exists or any call is still waiting to be dispatched
+/// (unless it was created with new_unref()), once the handle is dropped and the pending calls
+/// are done the callback is freed.
 pub struct Callback<X> where X: Send {
     canary: Arc<Canary>,
-    sender: Sender<CallbackEv>,
     _x: PhantomData<X>
 }
 
 impl<X> Callback<X> where X: Send + 'static {
     pub fn new<W,F>(c:&Core, w:W, f:F) -> Callback<X> where
-        F: 'static + Fn(&mut W,X),
+        F: 'static + FnMut(&mut W,X),
+        W: 'static,
+    {
+        Self::_new(c, CallbackImpl::new(w, f))
+    }
+    // A callback which may only be called once, after that the closure and w are dropped.
+    pub fn new_once<W,F>(c:&Core, w:W, f:F) -> Callback<X> where
+        F: 'static + FnOnce(&mut W,X),
+        W: 'static,
+    {
+        Self::_new(c, CallbackImpl::new_once(w, f))
+    }
+    /// Like new() but the callback doesn't keep the loop alive, calls made while the loop is
+    /// still running are delivered as usual.
+    pub fn new_unref<W,F>(c:&Core, w:W, f:F) -> Callback<X> where
+        F: 'static + FnMut(&mut W,X),
         W: 'static,
     {
-        let id = c.next_callback_id();
-        let canary = Arc::new(Canary {
-            callback_id: id,
-        });
-        c.register_callback(id, CallbackImpl::new(w, f, &canary));
-        Callback { canary, _x: PhantomData, sender: c.callback_sender.clone() }
+        let mut cbi = CallbackImpl::new(w, f);
+        cbi.keepalive = false;
+        Self::_new(c, cbi)
+    }
+    fn _new(c:&Core, cbi:CallbackImpl) -> Callback<X> {
+        Callback { canary: new_canary(c, cbi), _x: PhantomData }
+    }
+    pub(crate) fn id(&self) -> i32 { self.canary.callback_id }
+    /// Name the callback in Core::active_handles(), returns false if it belongs to another loop
+    /// or doesn't keep its loop alive.
+    pub fn label(&self, c:&Core, label: &str) -> bool {
+        c.loop_id == self.canary.loop_id && c.label_callback(self.canary.callback_id, label)
     }
     pub fn call(&self, x:X) {
-        match self.sender.send(CallbackEv::Req(CallbackReq {
-            x: Box::new(Some(x)),
-            canary: self.canary.clone()
-        })) {
+        Self::_call(self.canary.clone(), x)
+    }
+    // Consume the handle while making the call so that there's never a moment when the loop
+    // has received the request but the handle still exists.
+    pub fn call_once(self, x:X) {
+        Self::_call(self.canary, x)
+    }
+    fn _call(canary: Arc<Canary>, x:X) {
+        if let Some(q) = local_queue(canary.loop_id) {
+            q.borrow_mut().push_back(LocalEv::Req(LocalReq { x: Box::new(Some(x)), canary }));
+            return;
+        }
+        let sender = canary.sender.clone();
+        match sender.send(CallbackEv::Req(CallbackReq { x: Box::new(Some(x)), canary })) {
             Ok(_) => (),
             Err(e) => { warn!("Error making callback {:?}", &e); }
         }
-    } 
+    }
+}
+
+fn new_canary(c:&Core, mut cbi:CallbackImpl) -> Arc<Canary> {
+    cbi.async_cx = async_hooks::init();
+    let id = c.next_callback_id();
+    c.register_callback(id, cbi);
+    Arc::new(Canary { callback_id: id, sender: c.callback_sender.clone(), loop_id: c.loop_id })
+}
+
+// A callback which can only be called from the loop's own thread, so neither the callback nor
+// its argument need to be Send.
+pub struct LocalCallback<X> {
+    canary: Arc<Canary>,
+    queue: LocalQueue,
+    _x: PhantomData<X>
+}
+
+impl<X> LocalCallback<X> where X: 'static {
+    pub fn new<W,F>(c:&Core, w:W, f:F) -> LocalCallback<X> where
+        F: 'static + FnMut(&mut W,X),
+        W: 'static,
+    {
+        let canary = new_canary(c, CallbackImpl::new(w, f));
+        LocalCallback { canary, _x: PhantomData, queue: c.local_queue.clone() }
+    }
+    pub fn call(&self, x:X) {
+        let req = LocalReq { x: Box::new(Some(x)), canary: self.canary.clone() };
+        self.queue.borrow_mut().push_back(LocalEv::Req(req));
+    }
 }
\ No newline at end of file
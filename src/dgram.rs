@@ -1,4 +1,4 @@
-use std::rc::Rc;
+use std::rc::{ Rc, Weak };
 use std::cell::{ RefCell, RefMut };
 use std::collections::VecDeque;
 use std::net::SocketAddr;
@@ -9,29 +9,51 @@ use std;
 use std::io;
 use std::ops::Deref;
 use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::time::SystemTime;
 use std::str::FromStr;
 use std::io::ErrorKind;
+use std::net::ToSocketAddrs;
 use super::Token;
 
+use abort::{ AbortSignal, abortable };
 use callback::Callback;
+use future::{ promise, Promise, Resolver };
+use sys;
+use threadpool::queue_work;
 
-use node::{ Loop, Core };
+use node::{ Loop, Core, Resources };
 
 fn send_messages(pvt: &mut RefMut<SockPvt>) {
     loop {
         let st_ = pvt.send_queue.pop_front();
         if st_.is_none() { return; }
         let st = st_.unwrap();
-        match pvt.s.as_ref().unwrap().send_to(&st.msg.buf, &st.msg.sa) {
+        pvt.send_queue_bytes -= st.msg.buf.len();
+        if let Some((sig, id)) = st.abort.as_ref() {
+            // Its callback has been told already
+            if sig.aborted() { continue; }
+            sig.forget(*id);
+        }
+        let s = pvt.s.as_ref().unwrap();
+        let ret = match st.msg.meta.as_ref().and_then(|m| m.local.map(|l| (l, m.ifindex))) {
+            Some((from, ifindex)) => sys::send_from(s, &st.msg.buf, &st.msg.sa, &from, ifindex),
+            None => s.send_to(&st.msg.buf, &st.msg.sa)
+        };
+        match ret {
             Ok(size) => {
                 assert!(size == st.msg.buf.len());
+                pvt.stats.packets_sent += 1;
+                pvt.stats.bytes_sent += size as u64;
                 st.cb.call(Ok(()));
             },
             Err(e) => {
                 if e.kind() == ErrorKind::WouldBlock {
                     pvt.can_send = false;
+                    pvt.send_queue_bytes += st.msg.buf.len();
+                    pvt.send_queue.push_front(st);
                     return;
                 }
+                pvt.stats.errors += 1;
                 st.cb.call(Err(e));
                 //println!("err {:?}", e);
             }
@@ -39,28 +61,152 @@ fn send_messages(pvt: &mut RefMut<SockPvt>) {
     }
 }
 
+fn recv_messages(pvt: &mut SockPvt) {
+    loop {
+        let s = pvt.s.as_ref().unwrap();
+        let mut buf = BytesMut::with_capacity(2048);
+        let ret = if pvt.recv_meta {
+            sys::recv_from(s, unsafe { buf.bytes_mut() }).map(|(c, sa, m)| (c, sa, Some(m)))
+        } else {
+            unsafe { s.recv_from(buf.bytes_mut()) }.map(|(c, sa)| (c, sa, None))
+        };
+        match ret {
+            Ok((count, sa, meta)) => {
+                unsafe { buf.advance_mut(count); }
+                pvt.stats.packets_received += 1;
+                pvt.stats.bytes_received += count as u64;
+                if pvt.recv_buf.is_some() {
+                    for cb in &pvt.on_message {
+                        cb.call(Message { buf: buf.clone(), sa, meta });
+                    }
+                    queue_recv(pvt, Message { buf, sa, meta });
+                } else if pvt.on_message.len() == 1 {
+                    pvt.on_message[0].call(Message { buf, sa, meta });
+                } else {
+                    for cb in &pvt.on_message {
+                        cb.call(Message { buf: buf.clone(), sa, meta });
+                    }
+                }
+            },
+            Err(e) => {
+                if e.kind() != ErrorKind::WouldBlock {
+                    pvt.stats.errors += 1;
+                    error!("dgram recv_messages {:?}", &e);
+                }
+                break;
+            }
+        }
+    }
+}
+
+// Hand a message to the oldest recv_from() which is still waiting, or keep it for the next one.
+fn queue_recv(pvt: &mut SockPvt, msg: Message) {
+    while let Some(r) = pvt.recv_waiters.pop_front() {
+        if !r.is_canceled() { r.resolve(Ok(msg)); return; }
+    }
+    let buf = pvt.recv_buf.as_mut().unwrap();
+    buf.push_back(msg);
+    while buf.len() > pvt.max_recv_buf {
+        buf.pop_front();
+        pvt.stats.recv_dropped += 1;
+    }
+}
+
+const MAX_RECV_BUF: usize = 1024;
+
+fn close(pvt_: &Rc<RefCell<SockPvt>>) {
+    debug!("close()");
+    let mut pvt = pvt_.borrow_mut();
+    if pvt.closed { return; }
+    pvt.closed = true;
+    pvt.on_message.clear();
+    for st in pvt.send_queue.drain(..) {
+        st.cb.call(Err(io::Error::new(ErrorKind::NotConnected, "socket closed")));
+    }
+    pvt.send_queue_bytes = 0;
+    pvt.recv_buf = None;
+    for r in pvt.recv_waiters.drain(..) {
+        r.resolve(Err(io::Error::new(ErrorKind::NotConnected, "socket closed")));
+    }
+    if let Some(c) = pvt.core.as_ref() {
+        if pvt.event != Token(0) { let _ = c.deregister_event(&pvt.event); }
+    }
+    for cb in pvt.on_close.drain(..) { cb.call(()); }
+    // Nothing left for the scope to do
+    if let Some((res, t)) = pvt.owner.take() {
+        if let Some(res) = res.upgrade() { res.forget(t); }
+    }
+}
+
+// The first loop scope to use the socket owns it
+fn attach<L:Loop<L>>(l:&L, pvt: &mut RefMut<SockPvt>, rc: &Rc<RefCell<SockPvt>>) {
+    if pvt.core.is_some() { return; }
+    pvt.core = Some(l.core().clone());
+    // Disposing now would need the borrow which the caller holds
+    if l.is_closed() { error!("Socket used from a scope which is closed"); return; }
+    let w = Rc::downgrade(rc);
+    let t = l.on_dispose(move ||{ if let Some(pvt) = w.upgrade() { close(&pvt); } });
+    pvt.owner = Some((Rc::downgrade(l.resources()), t));
+}
 
 struct SendTo {
     msg: Message,
-    cb: Callback<io::Result<()>>
+    cb: Callback<io::Result<()>>,
+    abort: Option<(AbortSignal, usize)>
 }
 struct SockPvt {
     s: Option<Rc<mio::net::UdpSocket>>,
 
-    can_send_event: Token,
+    event: Token,
     can_send: bool,
-    can_recv_event: Token,
+    recv_meta: bool,
+    exclusive: bool,
 
     send_queue: VecDeque<SendTo>,
+    send_queue_bytes: usize,
+    max_send_queue: usize,
+    on_queue_full: QueueFull,
+    stats: SockStats,
     on_message: Vec<Callback<Message>>,
+    on_close: Vec<Callback<()>>,
+    on_listening: Vec<Callback<SocketAddr>>,
+    // Only buffered once recv_from() has been used
+    recv_buf: Option<VecDeque<Message>>,
+    max_recv_buf: usize,
+    recv_waiters: VecDeque<Resolver<io::Result<Message>>>,
     core: Option<Core>,
+    // The disposer in the scope which owns the socket
+    owner: Option<(Weak<Resources>, Token)>,
 
     closed: bool
 }
 
+/// What to do when send_to() is called and the send queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueFull {
+    /// Fail the oldest queued message and queue the new one
+    DropOldest,
+    /// Fail the new message
+    Error
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SockStats {
+    pub bytes_sent: u64,
+    pub packets_sent: u64,
+    pub bytes_received: u64,
+    pub packets_received: u64,
+    /// Messages failed because the send queue was full
+    pub packets_dropped: u64,
+    /// Received messages discarded from the recv_from() buffer because it was full
+    pub recv_dropped: u64,
+    pub errors: u64
+}
+
 pub struct SockBuilder {
     pvt: Rc<RefCell<SockPvt>>,
     af: Af,
+    proto: Proto
 }
 
 fn try_setup_core(pvt: &mut RefMut<SockPvt>, rc: &Rc<RefCell<SockPvt>>) {
@@ -68,47 +214,22 @@ fn try_setup_core(pvt: &mut RefMut<SockPvt>, rc: &Rc<RefCell<SockPvt>>) {
     if pvt.s.is_none() || pvt.core.is_none() { return; }
 
     // done already
-    if pvt.can_recv_event != Token(0) { return; }
+    if pvt.event != Token(0) { return; }
 
     let c = pvt.core.as_ref().unwrap().clone();
     let s = pvt.s.as_ref().unwrap().clone();
 
-    let can_send_cb = Callback::new(&c, rc.clone(), |pvt_,_|{
+    // Register once for both directions, epoll refuses a second registration of the same fd.
+    let cb = Callback::new(&c, rc.clone(), |pvt_,_|{
         let mut pvt = pvt_.borrow_mut();
-        pvt.can_send = true;
-        send_messages(&mut pvt);
-    });
-    pvt.can_send_event =
-        c.register_event(s.clone(), can_send_cb, Ready::writable(), PollOpt::edge()).unwrap();
-
-    let can_recv_cb = Callback::new(&c, rc.clone(), |pvt_,_| {
-        let pvt = pvt_.borrow_mut();
-        let s = pvt.s.as_ref().unwrap();
-        loop {
-            let mut buf = BytesMut::with_capacity(2048);
-            let ret = unsafe { s.recv_from(buf.bytes_mut()) };
-            match ret {
-                Ok((count, sa)) => {
-                    unsafe { buf.advance_mut(count); }
-                    if pvt.on_message.len() == 1 {
-                        pvt.on_message[0].call(Message { buf, sa });
-                    } else {
-                        for cb in &pvt.on_message {
-                            cb.call(Message { buf: buf.clone(), sa: sa.clone() });
-                        }
-                    }
-                },
-                Err(e) => {
-                    if e.kind() != ErrorKind::WouldBlock {
-                        error!("dgram can_recv_cb {:?}", &e);
-                    }
-                    break;
-                }
-            }
+        if !pvt.can_send {
+            pvt.can_send = true;
+            send_messages(&mut pvt);
         }
+        recv_messages(&mut pvt);
     });
-    pvt.can_recv_event =
-        c.register_event(s, can_recv_cb, Ready::readable(), PollOpt::edge()).unwrap();
+    pvt.event = c.register_event(s, cb, Ready::readable() | Ready::writable(), PollOpt::edge())
+        .unwrap();
 
     // you don't get a can_send event until you clog up the buffer first, so better send now.
     pvt.can_send = true;
@@ -124,84 +245,222 @@ impl Deref for Sock {
     fn deref(&self) -> &Self::Target { &self.s }
 }
 impl Sock {
-    pub fn on_message<L:Loop<L>,F:'static+Fn(&mut L,Message)>(&self, l:&L, f:F) -> &Sock {
+    pub fn on_message<L:Loop<L>,F:'static+FnMut(&mut L,Message)>(&self, l:&L, f:F) -> &Sock {
         self.bldr.on_message(l, f);
         self
     }
     pub fn send_to<L,F,A,B>(&self, l:&L, bm: B, a:A, f:F) -> &Sock where
         L: Loop<L>,
-        F: 'static + Fn(&mut L, io::Result<()>),
+        F: 'static + FnOnce(&mut L, io::Result<()>),
         A: AddrLike,
         B: Into<BytesMut>
     {
         self.bldr.send_to(l, bm, a, f);
         self
     }
-    pub fn close(&self) {
-        debug!("close()");
+    /// Like send_to() but if the signal fires before the message is sent, it is removed from
+    /// the send queue and f gets an Aborted error.
+    pub fn send_to_signal<L,F,A,B>(&self, l:&L, bm: B, a:A, signal: &AbortSignal, f:F) -> &Sock
+        where
+        L: Loop<L>,
+        F: 'static + FnOnce(&mut L, io::Result<()>),
+        A: AddrLike,
+        B: Into<BytesMut>
+    {
+        self.bldr.send_to_signal(l, bm, a, signal, f);
+        self
+    }
+    pub fn send_from<L,F,A,B>(&self, l:&L, bm: B, a:A, from: IpAddr, f:F) -> &Sock where
+        L: Loop<L>,
+        F: 'static + FnOnce(&mut L, io::Result<()>),
+        A: AddrLike,
+        B: Into<BytesMut>
+    {
+        self.bldr.send_from(l, bm, a, from, f);
+        self
+    }
+    /// Number of bytes waiting in the send queue
+    pub fn send_queue_size(&self) -> usize { self.bldr.pvt.borrow().send_queue_bytes }
+    /// Number of messages waiting in the send queue
+    pub fn send_queue_count(&self) -> usize { self.bldr.pvt.borrow().send_queue.len() }
+    pub fn stats(&self) -> SockStats { self.bldr.pvt.borrow().stats }
+    /// The address which the socket is bound to, useful after binding to port 0.
+    pub fn address(&self) -> io::Result<SocketAddr> { self.s.local_addr() }
+    pub fn on_close<L:Loop<L>,F:'static+FnOnce(&mut L)>(&self, l:&L, f:F) -> &Sock {
+        self.bldr.on_close(l, f);
+        self
+    }
+    /// A future for the next message. Once this has been called, messages which arrive while
+    /// no recv_from() is waiting are kept for the next call, up to max_recv_buffer() of them
+    /// (on_message handlers still get every message).
+    pub fn recv_from<L:Loop<L>>(&self, l:&L) -> Promise<io::Result<Message>> {
+        let (p, r) = promise();
+        let mut pvt = self.bldr.pvt.borrow_mut();
+        if pvt.closed {
+            r.resolve(Err(io::Error::new(ErrorKind::NotConnected, "socket closed")));
+            return p;
+        }
+        let msg = pvt.recv_buf.get_or_insert_with(VecDeque::new).pop_front();
+        match msg {
+            Some(msg) => r.resolve(Ok(msg)),
+            None => pvt.recv_waiters.push_back(r)
+        }
+        attach(l, &mut pvt, &self.bldr.pvt);
+        try_setup_core(&mut pvt, &self.bldr.pvt);
+        p
+    }
+    /// How many messages recv_from() keeps while none is waiting, default 1024, the oldest are
+    /// discarded beyond that. 0 stops buffering, for instance once only on_message is used.
+    pub fn max_recv_buffer(&self, count: usize) -> &Sock {
+        let mut pvt = self.bldr.pvt.borrow_mut();
+        pvt.max_recv_buf = count;
+        let extra = pvt.recv_buf.as_ref().map(|b| b.len().saturating_sub(count)).unwrap_or(0);
+        if let Some(b) = pvt.recv_buf.as_mut() { b.drain(..extra); }
+        pvt.stats.recv_dropped += extra as u64;
+        self
+    }
+    /// Close the socket, sends which are still queued are failed with NotConnected and then
+    /// the on_close handlers are called. Sockets are also closed when the scope which first
+    /// used them is closed.
+    pub fn close(&self) { close(&self.bldr.pvt); }
+    /// close() and call f once the socket has closed, like Node's socket.close(callback).
+    pub fn close_cb<L:Loop<L>,F:'static+FnOnce(&mut L)>(&self, l:&L, f:F) {
+        let cb = l.cb_once(|l,_|{ f(l) });
         let mut pvt = self.bldr.pvt.borrow_mut();
-        if pvt.s.is_none() { return; }
-        if pvt.core.is_none() { return; }
-        if pvt.closed { return; }
-        pvt.closed = true;
-        pvt.on_message.clear();
-        pvt.send_queue.clear();
-        let c = pvt.core.as_ref().unwrap();
-        match c.deregister_event(&pvt.can_send_event) {_=>()}
-        match c.deregister_event(&pvt.can_recv_event) {_=>()}
+        if pvt.closed { cb.call_once(()); return; }
+        pvt.on_close.push(cb);
+        drop(pvt);
+        self.close();
     }
 }
 
 impl SockBuilder {
-    pub fn on_message<L:Loop<L>,F:'static+Fn(&mut L,Message)>(&self, l:&L, f:F) -> &SockBuilder {
-        let c = l.core();
+    pub fn on_message<L:Loop<L>,F:'static+FnMut(&mut L,Message)>(&self, l:&L, f:F) -> &SockBuilder {
         let mut pvt = self.pvt.borrow_mut();
         if pvt.closed { error!("on_message() Socket already closed"); return self; }
-        pvt.on_message.push(Callback::new(c, rec!{ l: l.as_rc(), f:f }, |ctx,msg|{
-            (ctx.f)(&mut *ctx.l.borrow_mut(), msg);
-        }));
-        if pvt.core.is_none() { pvt.core = Some(c.clone()); }
+        pvt.on_message.push(l.cb(f));
+        attach(l, &mut pvt, &self.pvt);
         try_setup_core(&mut pvt, &self.pvt);
         self
     }
+    pub fn on_close<L:Loop<L>,F:'static+FnOnce(&mut L)>(&self, l:&L, f:F) -> &SockBuilder {
+        let mut pvt = self.pvt.borrow_mut();
+        if pvt.closed { error!("on_close() Socket already closed"); return self; }
+        pvt.on_close.push(l.cb_once(|l,_|{ f(l) }));
+        self
+    }
     pub fn send_to<L,F,A,B>(&self, l:&L, bm: B, a:A, f:F) -> &SockBuilder where
         L: Loop<L>,
-        F: 'static + Fn(&mut L, io::Result<()>),
+        F: 'static + FnOnce(&mut L, io::Result<()>),
         A: AddrLike,
         B: Into<BytesMut>
     {
+        self._send(l, bm, a, None, None, f)
+    }
+    pub fn send_to_signal<L,F,A,B>(&self, l:&L, bm: B, a:A, signal: &AbortSignal, f:F)
+        -> &SockBuilder where
+        L: Loop<L>,
+        F: 'static + FnOnce(&mut L, io::Result<()>),
+        A: AddrLike,
+        B: Into<BytesMut>
+    {
+        let f = abortable(l, signal, f);
+        self._send(l, bm, a, None, Some(signal), f)
+    }
+    pub fn send_from<L,F,A,B>(&self, l:&L, bm: B, a:A, from: IpAddr, f:F) -> &SockBuilder where
+        L: Loop<L>,
+        F: 'static + FnOnce(&mut L, io::Result<()>),
+        A: AddrLike,
+        B: Into<BytesMut>
+    {
+        self._send(l, bm, a, Some(MsgMeta { local: Some(from), ..MsgMeta::default() }), None, f)
+    }
+    fn _send<L,F,A,B>(&self, l:&L, bm: B, a:A, meta: Option<MsgMeta>,
+        signal: Option<&AbortSignal>, f:F) -> &SockBuilder where
+        L: Loop<L>,
+        F: 'static + FnOnce(&mut L, io::Result<()>),
+        A: AddrLike,
+        B: Into<BytesMut>
+    {
+        let cb = l.cb_once(f);
         let addr_str = a.to_string();
         let sa = match a.as_sockaddr(self.af) {
             Ok(sa) => sa,
-            Err(_e) => {
+            Err(e) => {
                 error!("send_to() Failed to parse address {}", &addr_str);
+                cb.call(Err(e));
                 return self;
             }
         };
-        let c = l.core();
         let mut pvt = self.pvt.borrow_mut();
-        if pvt.closed { error!("send_to() Socket already closed"); return self; }
-        pvt.send_queue.push_back(SendTo {
-            msg: bm.to_msg(sa),
-            cb: Callback::new(c, rec!{ l: l.as_rc(), f:f }, |ctx,res|{
-                (ctx.f)(&mut *ctx.l.borrow_mut(), res);
-            })
+        if pvt.closed {
+            cb.call(Err(io::Error::new(ErrorKind::NotConnected, "socket closed")));
+            return self;
+        }
+        if signal.map(|s| s.aborted()).unwrap_or(false) { return self; }
+        let mut msg = bm.to_msg(sa);
+        msg.meta = meta;
+        let abort = signal.map(|sig|{
+            let w = Rc::downgrade(&self.pvt);
+            let id = sig.on_abort(move ||{
+                let rc = match w.upgrade() { Some(rc) => rc, None => { return; } };
+                // If it's busy, send_messages() skips the aborted sends instead
+                if let Ok(mut pvt) = rc.try_borrow_mut() {
+                    pvt.send_queue.retain(|st| !st.abort.as_ref().map(|a| a.0.aborted())
+                        .unwrap_or(false));
+                    pvt.send_queue_bytes = pvt.send_queue.iter().map(|st| st.msg.buf.len()).sum();
+                };
+            });
+            (sig.clone(), id)
         });
+        let st = SendTo { msg, cb, abort };
+        if pvt.max_send_queue > 0 && pvt.send_queue.len() >= pvt.max_send_queue {
+            pvt.stats.packets_dropped += 1;
+            let dropped = match pvt.on_queue_full {
+                QueueFull::Error => st,
+                QueueFull::DropOldest => {
+                    let old = pvt.send_queue.pop_front().unwrap();
+                    pvt.send_queue_bytes -= old.msg.buf.len();
+                    pvt.send_queue_bytes += st.msg.buf.len();
+                    pvt.send_queue.push_back(st);
+                    old
+                }
+            };
+            dropped.cb.call(Err(io::Error::new(ErrorKind::WouldBlock, "send queue full")));
+            return self;
+        }
+        pvt.send_queue_bytes += st.msg.buf.len();
+        pvt.send_queue.push_back(st);
         if pvt.can_send {
             send_messages(&mut pvt);
         } else {
-            if pvt.core.is_none() { pvt.core = Some(c.clone()); }
+            attach(l, &mut pvt, &self.pvt);
             try_setup_core(&mut pvt, &self.pvt);
         }
         self
     }
+    /// Limit the send queue to count messages (0 is unlimited), when it is full a message is
+    /// failed with WouldBlock according to the policy.
+    pub fn max_send_queue(self, count: usize, policy: QueueFull) -> Self {
+        {
+            let mut pvt = self.pvt.borrow_mut();
+            pvt.max_send_queue = count;
+            pvt.on_queue_full = policy;
+        }
+        self
+    }
+    /// Receive ancillary data (see MsgMeta) with each message, must be set before bind().
+    pub fn recv_meta(self, it: bool) -> Self { self.pvt.borrow_mut().recv_meta = it; self }
     pub fn _bind(self, addr: &SocketAddr) -> io::Result<Sock> {
-        let s = mio::net::UdpSocket::bind(addr)?;
+        let s = sys::socket(self.af, self.proto, addr, self.pvt.borrow().exclusive)?;
+        if self.pvt.borrow().recv_meta { sys::enable_meta(&s, self.af)?; }
+        let local = s.local_addr()?;
         let rc = Rc::new(s);
         {
             let mut pvt = self.pvt.borrow_mut();
             pvt.s = Some(rc.clone());
             try_setup_core(&mut pvt, &self.pvt);
+            for cb in pvt.on_listening.drain(..) { cb.call(local); }
         }
         Ok(Sock { s: rc, bldr: self })
     }
@@ -212,40 +471,112 @@ impl SockBuilder {
             Err(e) => Err(e)
         }
     }
+    /// Bind without blocking the loop, hostnames are resolved on the loop's thread pool (see
+    /// threadpool::queue_work()). The callback gets the bound socket, the on_listening handlers
+    /// are called once it is bound.
+    pub fn bind_async<L,F,T>(self, l:&L, t:T, f:F) where
+        L: Loop<L>,
+        F: 'static + FnOnce(&mut L, io::Result<Sock>),
+        T: 'static + AddrLike + Send
+    {
+        let af = self.af;
+        let bldr = self;
+        queue_work(l, move || t.resolve(af), move |l,res: io::Result<SocketAddr>|{
+            f(l, res.and_then(|sa| bldr._bind(&sa)));
+        });
+    }
+    /// Like bind_async() but if the signal fires before the lookup finishes, f gets an Aborted
+    /// error and the late result is dropped.
+    pub fn bind_async_signal<L,F,T>(self, l:&L, t:T, signal: &AbortSignal, f:F) where
+        L: Loop<L>,
+        F: 'static + FnOnce(&mut L, io::Result<Sock>),
+        T: 'static + AddrLike + Send
+    {
+        let f = abortable(l, signal, f);
+        self.bind_async(l, t, f)
+    }
+    /// Called with the local address once the socket is bound
+    pub fn on_listening<L,F>(&self, l:&L, f:F) -> &SockBuilder where
+        L: Loop<L>,
+        F: 'static + FnOnce(&mut L, SocketAddr)
+    {
+        let mut pvt = self.pvt.borrow_mut();
+        pvt.on_listening.push(l.cb_once(f));
+        self
+    }
+    /// If false, the address is bound with SO_REUSEPORT so that sockets in other loops (or
+    /// processes) can bind it too and the kernel spreads incoming messages between them.
+    pub fn exclusive(self, it: bool) -> Self { self.pvt.borrow_mut().exclusive = it; self }
 }
 
+/// Create a socket, afs is one of udp4, udp6, icmp4, icmp6 (unprivileged ICMP echo sockets,
+/// see net.ipv4.ping_group_range on Linux), raw4 or raw6 (raw ICMP, needs CAP_NET_RAW).
+/// Raw IPv4 sockets receive messages with the IP header in front.
 pub fn create_socket(afs: &'static str) -> io::Result<SockBuilder> {
-    let af = match afs {
-        "udp4" => Af::Inet,
-        "udp6" => Af::Inet6,
-        _ => { return Err(io::Error::new(ErrorKind::InvalidInput, "expecting udp4 or udp6")); }
+    let (af, proto) = match afs {
+        "udp4" => (Af::Inet, Proto::Udp),
+        "udp6" => (Af::Inet6, Proto::Udp),
+        "icmp4" => (Af::Inet, Proto::Icmp),
+        "icmp6" => (Af::Inet6, Proto::Icmp),
+        "raw4" => (Af::Inet, Proto::Raw),
+        "raw6" => (Af::Inet6, Proto::Raw),
+        _ => {
+            return Err(io::Error::new(ErrorKind::InvalidInput,
+                "expecting udp4, udp6, icmp4, icmp6, raw4 or raw6"));
+        }
     };
     Ok(SockBuilder {
         af,
+        proto,
         pvt: Rc::new(RefCell::new(SockPvt {
             s: None,
 
-            can_send_event: Token(0),
+            event: Token(0),
             can_send: false,
-            can_recv_event: Token(0),
+            recv_meta: false,
+            exclusive: true,
 
             send_queue: VecDeque::new(),
+            send_queue_bytes: 0,
+            max_send_queue: 0,
+            on_queue_full: QueueFull::DropOldest,
+            stats: SockStats::default(),
             on_message: Vec::new(),
+            on_close: Vec::new(),
+            on_listening: Vec::new(),
+            recv_buf: None,
+            max_recv_buf: MAX_RECV_BUF,
+            recv_waiters: VecDeque::new(),
             core: None,
+            owner: None,
 
             closed: false
         }))
     })
 }
 
-///
-
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Af { Inet, Inet6 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Proto { Udp, Icmp, Raw }
+
 pub trait AddrLike {
+    #[allow(clippy::wrong_self_convention)]
     fn as_sockaddr(self, af: Af) -> io::Result<SocketAddr>;
     fn to_string(&self) -> String;
+    // Like as_sockaddr() but may look up hostnames, so it can block.
+    fn resolve(self, af: Af) -> io::Result<SocketAddr> where Self: Sized { self.as_sockaddr(af) }
+}
+
+fn lookup(port: u16, host: &str, af: Af) -> io::Result<SocketAddr> {
+    for sa in (host, port).to_socket_addrs()? {
+        match (af, sa) {
+            (Af::Inet, SocketAddr::V4(_)) | (Af::Inet6, SocketAddr::V6(_)) => { return Ok(sa); }
+            _ => ()
+        }
+    }
+    Err(io::Error::new(ErrorKind::NotFound, format!("no address found for {}", host)))
 }
 impl AddrLike for u16 {
     fn to_string(&self) -> String { <Self as std::string::ToString>::to_string(self) }
@@ -259,6 +590,11 @@ impl AddrLike for u16 {
 impl AddrLike for &'static str {
     fn to_string(&self) -> String { <Self as std::string::ToString>::to_string(self) }
     fn as_sockaddr(self, af: Af) -> io::Result<SocketAddr> { (0, self).as_sockaddr(af) }
+    fn resolve(self, af: Af) -> io::Result<SocketAddr> { (0, self).resolve(af) }
+}
+impl AddrLike for SocketAddr {
+    fn to_string(&self) -> String { <Self as std::string::ToString>::to_string(self) }
+    fn as_sockaddr(self, _af: Af) -> io::Result<SocketAddr> { Ok(self) }
 }
 impl AddrLike for (u16, &'static str) {
     fn to_string(&self) -> String { format!("({},{})", self.0, self.1) }
@@ -270,12 +606,38 @@ impl AddrLike for (u16, &'static str) {
         };
         Ok(SocketAddr::new(res, port))
     }
+    fn resolve(self, af: Af) -> io::Result<SocketAddr> {
+        match self.as_sockaddr(af) {
+            Ok(sa) => Ok(sa),
+            Err(_) => lookup(self.0, self.1, af)
+        }
+    }
 }
 
 
+/// Ancillary data which comes with a received message if the socket was created with
+/// recv_meta(true). When sending, local and ifindex select the source address / interface.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MsgMeta {
+    /// The local (destination) address which the datagram was sent to
+    pub local: Option<IpAddr>,
+    /// Index of the interface which the datagram arrived on, 0 if unknown
+    pub ifindex: u32,
+    /// IPv4 TTL or IPv6 hop limit
+    pub ttl: Option<u8>,
+    /// Kernel receive timestamp
+    pub timestamp: Option<SystemTime>,
+    /// ECN bits of the TOS / traffic class field
+    pub ecn: Option<u8>,
+    /// The control data didn't fit in the receive buffer and was cut short, so fields which
+    /// are None may just have been lost
+    pub truncated: bool
+}
+
 pub struct Message {
     pub sa: SocketAddr,
-    pub buf: BytesMut
+    pub buf: BytesMut,
+    pub meta: Option<MsgMeta>
 }
 pub trait MsgLike {
     fn to_msg(self, sa: SocketAddr) -> Message;
@@ -283,6 +645,6 @@ pub trait MsgLike {
 impl<T> MsgLike for T where T: Into<BytesMut> {
     fn to_msg(self, sa: SocketAddr) -> Message {
         let buf = self.into();
-        Message { sa, buf }
+        Message { sa, buf, meta: None }
     }
 }
\ No newline at end of file
@@ -0,0 +1,133 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{ Arc, Mutex };
+use std::task::{ Context, Poll, Waker, Wake };
+
+use callback::Callback;
+use node::{ Loop, Core };
+
+type Task = Rc<RefCell<Option<Pin<Box<dyn Future<Output=()>>>>>>;
+
+// Waking posts a call to the loop which polls the task again. A waker holds a callback so the
+// loop stays alive while anything could still wake the task, once every waker is gone the task
+// can never progress and it is dropped along with the callback.
+struct TaskWaker {
+    cb: Mutex<Callback<()>>
+}
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) { self.wake_by_ref() }
+    fn wake_by_ref(self: &Arc<Self>) {
+        match self.cb.lock() {
+            Ok(cb) => cb.call(()),
+            Err(e) => { warn!("Waker lock poisoned {:?}", &e); }
+        }
+    }
+}
+
+fn mk_waker(c: &Core, task: Task) -> Waker {
+    let cb = Callback::new(c, (task, c.clone()), |w,_|{ poll_task(&w.0, &w.1) });
+    Waker::from(Arc::new(TaskWaker { cb: Mutex::new(cb) }))
+}
+
+fn poll_task(task: &Task, c: &Core) {
+    let waker = mk_waker(c, task.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut t = task.borrow_mut();
+    let done = match t.as_mut() {
+        Some(f) => f.as_mut().poll(&mut cx).is_ready(),
+        None => { return; }
+    };
+    if done { *t = None; }
+}
+
+/// Run a future on the loop's thread, it is first polled on the next turn of the loop.
+pub fn spawn_local<L,F>(l:&L, f:F) where
+    L: Loop<L>,
+    F: 'static + Future<Output=()>
+{
+    let task: Task = Rc::new(RefCell::new(Some(Box::pin(f))));
+    mk_waker(l.core(), task).wake();
+}
+
+struct Slot<T> {
+    val: Option<T>,
+    waker: Option<Waker>
+}
+
+/// A value which is delivered later by a Resolver on the same thread.
+pub struct Promise<T> {
+    s: Rc<RefCell<Slot<T>>>
+}
+/// The sending half of a Promise, if it is dropped without resolving the promise never
+/// completes and whatever is awaiting it is allowed to be dropped.
+pub struct Resolver<T> {
+    s: Rc<RefCell<Slot<T>>>
+}
+
+pub fn promise<T>() -> (Promise<T>, Resolver<T>) {
+    let s = Rc::new(RefCell::new(Slot { val: None, waker: None }));
+    (Promise { s: s.clone() }, Resolver { s })
+}
+
+impl<T> Future for Promise<T> {
+    type Output = T;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut s = self.s.borrow_mut();
+        match s.val.take() {
+            Some(t) => Poll::Ready(t),
+            // Nothing can resolve it once the resolver has gone, so don't keep the task alive
+            None if Rc::strong_count(&self.s) == 1 => Poll::Pending,
+            None => {
+                s.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Resolver<T> {
+    /// True if the promise has been dropped so nobody will see the value
+    pub fn is_canceled(&self) -> bool { Rc::strong_count(&self.s) == 1 }
+    pub fn resolve(self, t:T) {
+        let waker = {
+            let mut s = self.s.borrow_mut();
+            s.val = Some(t);
+            s.waker.take()
+        };
+        if let Some(w) = waker { w.wake(); }
+    }
+}
+impl<T> Drop for Resolver<T> {
+    fn drop(&mut self) {
+        // Break the cycle slot -> waker -> task -> promise -> slot
+        let waker = self.s.borrow_mut().waker.take();
+        drop(waker);
+    }
+}
+
+/// Turn a callback API into a future. start is given the completion callback to pass on,
+/// for example `promisify(|done| { sock.send_to(l, buf, addr, done); })`.
+pub fn promisify<L,T,S>(start:S) -> Promise<T> where
+    L: Loop<L>,
+    T: 'static,
+    S: FnOnce(Box<dyn FnOnce(&mut L, T)>)
+{
+    let (p, r) = promise();
+    start(Box::new(move |_,t|{ r.resolve(t) }));
+    p
+}
+
+/// Turn a function which returns a future into a callback, each call spawns the future on the
+/// loop. The future can't borrow the loop, use `l.as_rc()` to reach its state.
+pub fn callbackify<L,X,F,R>(mut f:F) -> impl FnMut(&mut L, X) where
+    L: Loop<L>,
+    F: 'static + FnMut(&mut L, X) -> R,
+    R: 'static + Future<Output=()>
+{
+    move |l,x|{
+        let fut = f(l, x);
+        spawn_local(&*l, fut);
+    }
+}
@@ -1,21 +1,37 @@
 extern crate mio;
 extern crate mio_extras;
 extern crate bytes;
+extern crate libc;
+extern crate noders_macros;
+// So that the macros' ::noders paths work here too
+extern crate self as noders;
+
+#[doc(hidden)] pub use noders_macros::record;
+pub use noders_macros::{ main, test };
 
 // Same as an mio token, but exported to downstream libraries
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Token (pub usize);
-impl Into<mio::Token> for Token { fn into(self) -> mio::Token { mio::Token(self.0) } }
+impl From<Token> for mio::Token { fn from(val: Token) -> Self { mio::Token(val.0) } }
 impl From<mio::Token> for Token { fn from(val: mio::Token) -> Token { Token(val.0) } }
-impl Into<usize> for Token { fn into(self) -> usize { self.0 } }
+impl From<Token> for usize { fn from(val: Token) -> Self { val.0 } }
 impl From<usize> for Token { fn from(val: usize) -> Token { Token(val) } }
 
 #[macro_use] pub mod macros_internal;
 #[macro_use] pub mod macros;
 pub mod node;
 pub mod callback;
+pub mod future;
 pub mod time;
 pub mod dgram;
+pub mod ping;
+pub mod worker;
+pub mod threadpool;
+pub mod remote;
+pub mod abort;
+pub mod perf;
+pub mod async_hooks;
+mod sys;
 
 pub fn module() -> node::ModuleCfg { node::module() }
 
@@ -37,9 +53,14 @@ mod tests2 {
 #[cfg(test)]
 mod tests {
     use super::Token;
+    use std::io;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
     use node::{ Loop, module };
     use time::{ set_timeout, set_interval, clear_timeout };
     use dgram::*;
+    use ping::create_pinger;
 
     struct MyObj {
         i: u32
@@ -77,6 +98,132 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_udp_meta() {
+        const PORT: u16 = 6668;
+        module().run((), |s| {
+            let sock = create_socket("udp4").unwrap().recv_meta(true).bind((PORT, "0.0.0.0")).unwrap();
+            let sock2 = create_socket("udp4").unwrap().recv_meta(true).bind("0.0.0.0").unwrap();
+            s.with_scope(rec!{
+                sock: sock,
+                sock2: sock2
+            }, |s| {
+                s.sock.on_message(s, |s,msg|{
+                    let meta = msg.meta.unwrap();
+                    println!("Received message! {:?} from {:?} {:?}", msg.buf, msg.sa, meta);
+                    assert_eq!(meta.local, Some("127.0.0.1".parse().unwrap()));
+                    assert!(meta.ttl.is_some() && meta.timestamp.is_some() && !meta.truncated);
+                    s.sock.send_from(s, "pong", msg.sa, meta.local.unwrap(), |s,res|{
+                        res.unwrap();
+                        s.sock.close();
+                    });
+                });
+                s.sock2.on_message(s, |s,msg|{
+                    assert_eq!(msg.sa.port(), PORT);
+                    assert_eq!(&msg.buf[..], b"pong");
+                    s.sock2.close();
+                });
+                s.sock2.send_to(s, "ping", (PORT, "127.0.0.1"), |_,res|{ res.unwrap(); });
+            });
+        });
+    }
+
+    #[test]
+    fn test_udp_stats() {
+        const PORT: u16 = 6669;
+        module().run((), |s| {
+            let sock = create_socket("udp4").unwrap().bind((PORT, "0.0.0.0")).unwrap();
+            let sock2 = create_socket("udp4").unwrap()
+                .max_send_queue(16, QueueFull::Error).bind("0.0.0.0").unwrap();
+            s.with_scope(rec!{
+                sock: sock,
+                sock2: sock2
+            }, |s| {
+                // Keeps one message for recv_from(), the other is discarded
+                drop(s.sock.max_recv_buffer(1).recv_from(s));
+                s.sock.on_message(s, |s,_msg|{
+                    let stats = s.sock.stats();
+                    if stats.packets_received < 2 { return; }
+                    assert_eq!(stats.bytes_received, 10);
+                    assert_eq!((stats.recv_dropped, stats.packets_dropped), (1, 0));
+                    assert_eq!(s.sock2.stats().packets_sent, 2);
+                    assert_eq!(s.sock2.send_queue_count(), 0);
+                    assert_eq!(s.sock2.send_queue_size(), 0);
+                    s.sock.close();
+                    s.sock2.close();
+                });
+                s.sock2.send_to(s, "Hello", (PORT, "127.0.0.1"), |_,res|{ res.unwrap(); });
+                s.sock2.send_to(s, "world", (PORT, "127.0.0.1"), |_,res|{ res.unwrap(); });
+            });
+        });
+    }
+
+    #[test]
+    fn test_udp_close() {
+        module().run((), |s| {
+            let sock = create_socket("udp4").unwrap().bind((0, "127.0.0.1")).unwrap();
+            assert_ne!(sock.address().unwrap().port(), 0);
+            s.with_scope(rec!{
+                sock: sock,
+                closed: false
+            }, |s| {
+                s.sock.on_close(s, |s|{
+                    s.closed = true;
+                });
+                s.sock.close_cb(s, |s|{ assert!(s.closed); });
+                s.sock.send_to(s, "Hello", (9, "127.0.0.1"), |s,res|{
+                    assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotConnected);
+                    assert!(s.closed);
+                });
+            });
+        });
+    }
+
+    #[test]
+    #[ignore = "needs ICMP sockets (net.ipv4.ping_group_range) or CAP_NET_RAW, run with --ignored"]
+    fn test_ping() {
+        module().run((), |s| {
+            let pinger = create_pinger(s, "icmp4").unwrap();
+            s.with_scope(rec!{
+                pinger: pinger
+            }, |s| {
+                s.pinger.ping(s, "127.0.0.1", 1000, |s,res|{
+                    println!("Ping reply in {:?} raw: {}", res, s.pinger.is_raw());
+                    assert!(res.is_ok());
+                    // The answered ping's timer is gone from the scope
+                    assert!(s.resources().is_empty());
+                    s.pinger.close();
+                });
+            });
+        });
+    }
+
+    #[test]
+    fn test_udp_bind_async() {
+        const PORT: u16 = 6670;
+        module().run((), |s| {
+            s.with_scope(rec!{
+                sock: None::<Sock>,
+                listening: false
+            }, |s| {
+                let b = create_socket("udp4").unwrap().exclusive(false);
+                b.on_listening(s, |s,sa|{
+                    assert_eq!(sa.port(), PORT);
+                    s.listening = true;
+                    // Shared binding, a second socket can bind the same port
+                    let s2 = create_socket("udp4").unwrap().exclusive(false).bind(sa).unwrap();
+                    s2.close();
+                    assert!(create_socket("udp4").unwrap().bind(sa).is_err());
+                    s.sock.as_ref().unwrap().close();
+                });
+                b.bind_async(s, (PORT, "localhost"), |s,res|{
+                    assert!(!s.listening);
+                    s.sock = Some(res.unwrap());
+                });
+            });
+        });
+    }
+
     #[test]
     fn test_udp_thread() {
         const PORT: u16 = 6667;
@@ -106,6 +253,754 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_local_cb() {
+        module().run(rec!{
+            n: 0
+        }, |s| {
+            // Rc is not Send so this can only work through the local queue
+            let cb = s.local_cb(|s, x: Rc<u32>| {
+                s.n += *x;
+                assert!(s.n == 2 || s.n == 5);
+            });
+            cb.call(Rc::new(2));
+            cb.call(Rc::new(3));
+        });
+    }
+
+    #[test]
+    fn test_fn_once_mut() {
+        module().run(rec!{
+            out: String::new(),
+            ticks: 0,
+            x: Token(0)
+        }, |s| {
+            // Moves its captured String out, so FnOnce
+            let owned = String::from("moved");
+            set_timeout(s, move |s,_|{ s.out = owned; }, 10);
+            // Mutates its own state without a Cell, so FnMut
+            let mut n = 0;
+            s.x = set_interval(s, move |s,_|{
+                n += 1;
+                s.ticks = n;
+                if n == 3 {
+                    assert_eq!(s.out, "moved");
+                    clear_timeout(s, s.x);
+                }
+            }, 20);
+        });
+    }
+
+    #[test]
+    fn test_cb_lifetime() {
+        use std::sync::Arc;
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let done = Arc::new(AtomicUsize::new(0));
+        let done2 = done.clone();
+        module().run(done2, |s| {
+            // A call in flight when the handle is dropped is still delivered
+            let cb = s.cb(|_, x: usize| { CALLS.fetch_add(x, Ordering::SeqCst); });
+            cb.call(1);
+            drop(cb);
+            // A one-shot callback called again is ignored rather than panicking
+            let once = s.cb_once(|s, x: usize| {
+                CALLS.fetch_add(x, Ordering::SeqCst);
+                s.fetch_add(1, Ordering::SeqCst);
+            });
+            once.call(10);
+            once.call(100);
+            // Dropped on another thread after the loop has gone idle, this must wake the loop
+            let late = s.cb(|_, _: ()| { panic!("never called"); });
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                drop(late);
+            });
+        });
+        assert_eq!(CALLS.load(Ordering::SeqCst), 11);
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_future() {
+        use std::future::{ Future, poll_fn };
+        use std::pin::Pin;
+        use std::task::Poll;
+        use future::{ spawn_local, promise };
+        use time::sleep;
+        module().run(rec!{
+            got: String::new()
+        }, |s| {
+            let sock = create_socket("udp4").unwrap().bind((6671, "127.0.0.1")).unwrap();
+            let mut recv = sock.recv_from(s);
+            let mut slept = sleep(s, 20);
+            let mut sent = false;
+            let s_ = s.as_rc();
+            // What `sleep(s, 20).await; send; sock.recv_from(s).await` would be in an async fn
+            spawn_local(s, poll_fn(move |cx| {
+                if !sent {
+                    if Pin::new(&mut slept).poll(cx).is_pending() { return Poll::Pending; }
+                    sent = true;
+                    sock.send_to(&*s_.borrow(), "hi", (6671, "127.0.0.1"), |_,res|{
+                        res.unwrap();
+                    });
+                }
+                match Pin::new(&mut recv).poll(cx) {
+                    Poll::Pending => Poll::Pending,
+                    Poll::Ready(msg) => {
+                        let msg = msg.unwrap();
+                        s_.borrow_mut().got = String::from_utf8(msg.buf.to_vec()).unwrap();
+                        assert_eq!(s_.borrow().got, "hi");
+                        sock.close();
+                        Poll::Ready(())
+                    }
+                }
+            }));
+            // Never woken, so dropped once the loop has nothing else to do
+            let (p, r) = promise::<()>();
+            spawn_local(s, p);
+            drop(r);
+        });
+    }
+
+    #[test]
+    fn test_promisify() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{ Context, Poll };
+        use future::{ spawn_local, promisify, callbackify, Promise };
+        use time::sleep;
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+        // A hand written `async { assert!(send.await.is_ok()) }`
+        struct Sent(Promise<io::Result<()>>, Sock);
+        impl Future for Sent {
+            type Output = ();
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+                Pin::new(&mut self.0).poll(cx).map(|res| {
+                    res.unwrap();
+                    self.1.close();
+                })
+            }
+        }
+        module().run((), |s| {
+            let sock = create_socket("udp4").unwrap().bind((0, "127.0.0.1")).unwrap();
+            let sa = sock.address().unwrap();
+            let p = promisify(|done| { sock.send_to(s, "hi", sa, done); });
+            spawn_local(s, Sent(p, sock));
+
+            set_timeout(s, callbackify(|s, _| {
+                FIRED.fetch_add(1, Ordering::SeqCst);
+                sleep(s, 10)
+            }), 10);
+        });
+        assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_worker() {
+        use worker::{ worker, MessagePort };
+        use node::Scope;
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        static EXITS: AtomicUsize = AtomicUsize::new(0);
+        module().run(rec!{
+            sum: 0
+        }, |s| {
+            fn echo(s: &mut Scope<()>, port: MessagePort<u32, u32>) {
+                let port = Rc::new(port);
+                let p2 = port.clone();
+                port.on_message(s, move |_, n| {
+                    p2.post_message(n * 2);
+                    if n == 3 { p2.close(); }
+                });
+            }
+            let w = worker(s, (), echo).unwrap();
+            w.post_message(1).post_message(2).post_message(3);
+            w.on_message(s, |s, n| { s.sum += n; });
+            w.on_exit(s, |s, res| {
+                res.unwrap();
+                assert_eq!(s.sum, 12);
+                EXITS.fetch_add(1, Ordering::SeqCst);
+            });
+
+            // Would run forever if it wasn't terminated
+            fn forever(s: &mut Scope<()>, _port: MessagePort<(), ()>) {
+                set_interval(s, |_,_|{}, 10);
+            }
+            let w = worker(s, (), forever).unwrap();
+            w.on_exit(s, |_, res| { res.unwrap(); EXITS.fetch_add(1, Ordering::SeqCst); });
+            set_timeout(s, move |_,_|{ w.terminate(); }, 30);
+
+            fn crash(s: &mut Scope<()>, _port: MessagePort<(), ()>) {
+                set_timeout(s, |_,_|{ panic!("boom"); }, 0);
+            }
+            worker(s, (), crash).unwrap().on_exit(s, |_, res| {
+                assert!(res.unwrap_err().to_string().contains("boom"));
+                EXITS.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+        assert_eq!(EXITS.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_threadpool() {
+        use threadpool::queue_work;
+        use std::collections::HashSet;
+        module().threadpool_size(2).run(rec!{
+            sum: 0,
+            done: 0,
+            threads: HashSet::new()
+        }, |s| {
+            for i in 1..9u64 {
+                queue_work(s, move || {
+                    thread::sleep(Duration::from_millis(5));
+                    (i * i, thread::current().id())
+                }, |s, (sq, tid)| {
+                    s.sum += sq;
+                    s.done += 1;
+                    s.threads.insert(tid);
+                    if s.done == 8 {
+                        assert_eq!(s.sum, 204);
+                        assert!(s.threads.len() <= 2);
+                        assert_eq!(s.core().threadpool().size(), 2);
+                    }
+                });
+            }
+            queue_work(s, || { panic!("lost"); }, |_, ()| { panic!("not called"); });
+        });
+
+        // Shutting down waits for the running job but not for the queued ones
+        let (_, h) = module().threadpool_size(1).spawn((), |s| {
+            for _ in 0..20 {
+                queue_work(s, || { thread::sleep(Duration::from_millis(100)); }, |_, ()|{});
+            }
+        }).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        let t0 = ::std::time::Instant::now();
+        h.request_shutdown();
+        h.join().unwrap();
+        assert!(t0.elapsed() < Duration::from_millis(500), "{:?}", t0.elapsed());
+    }
+
+    #[test]
+    fn test_loop_handle() {
+        module().run((), |s| {
+            let (n, h) = s.module().spawn(5u32, |s| {
+                set_interval(s, |_,_|{}, 10);
+                **s + 2
+            }).unwrap();
+            assert_eq!(n, 7);
+            assert!(h.is_running());
+            set_timeout(s, move |_,_|{
+                assert!(!h.wait_timeout(Duration::from_millis(1)));
+                h.request_shutdown();
+                assert!(h.wait_timeout(Duration::from_secs(5)));
+                assert!(!h.is_running());
+                h.join().unwrap();
+            }, 30);
+
+            let (_, h) = s.module().spawn((), |s| {
+                set_timeout(s, |_,_|{ panic!("child loop panicked"); }, 10);
+            }).unwrap();
+            set_timeout(s, move |_,_|{ assert!(h.join().is_err()); }, 30);
+
+            assert!(s.module().spawn((), |_| { panic!("child init panicked"); }).is_err());
+        });
+    }
+
+    #[test]
+    fn test_remote() {
+        use std::sync::atomic::{ AtomicBool, Ordering };
+        static RAN: AtomicBool = AtomicBool::new(false);
+        fn is_send_sync<T:Send+Sync>(_: &T) {}
+        module().run(rec!{
+            n: 0
+        }, |s| {
+            let r = s.remote();
+            is_send_sync(&r);
+            let r2 = r.clone();
+            thread::spawn(move || {
+                r.post(|s| { s.n += 1; });
+                r2.set_timeout_remote(10, |s,_|{
+                    s.n += 10;
+                    assert_eq!(s.n, 11);
+                    RAN.store(true, Ordering::SeqCst);
+                });
+            });
+        });
+        assert!(RAN.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_run_modes() {
+        use node::{ RunMode, on_before_exit, on_exit };
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        static BEFORE: AtomicUsize = AtomicUsize::new(0);
+        static EXIT: AtomicUsize = AtomicUsize::new(0);
+        let (core, interval) = module().create(rec!{
+            ticks: 0
+        }, |s| {
+            on_before_exit(s, |s| {
+                // The first time there's more to do
+                if BEFORE.fetch_add(1, Ordering::SeqCst) == 0 { set_timeout(s, |_,_|{}, 5); }
+            });
+            on_exit(s, |s| {
+                assert_eq!(s.ticks, 3);
+                EXIT.fetch_add(1, Ordering::SeqCst);
+            });
+            set_interval(s, |s,_|{
+                s.ticks += 1;
+                if s.ticks == 3 { s.core().stop(); }
+            }, 50)
+        });
+        // Waits for the first tick
+        assert!(core.run(RunMode::Once));
+        // As if embedded in some other loop which must not block
+        for _ in 0..10 {
+            assert!(core.run(RunMode::NoWait));
+            thread::sleep(Duration::from_millis(1));
+        }
+        // Runs until stop()
+        assert!(core.run(RunMode::Default));
+        assert_eq!(EXIT.load(Ordering::SeqCst), 0);
+        assert!(core.deregister_event(&interval).unwrap());
+        assert!(!core.run(RunMode::Default));
+        assert!(!core.is_alive());
+        assert_eq!(BEFORE.load(Ordering::SeqCst), 2);
+        assert_eq!(EXIT.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_run_nowait_exit() {
+        use node::{ RunMode, on_before_exit, on_exit };
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        static EXITS: AtomicUsize = AtomicUsize::new(0);
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct D;
+        impl Drop for D {
+            fn drop(&mut self) { DROPS.fetch_add(1, Ordering::SeqCst); }
+        }
+        let (core, _) = module().create(rec!{ d: D }, |s| {
+            on_before_exit(s, |_|{});
+            on_exit(s, |_|{ EXITS.fetch_add(1, Ordering::SeqCst); });
+            set_timeout(s, |_,_|{}, 5);
+        });
+        while core.run(RunMode::NoWait) { thread::sleep(Duration::from_millis(1)); }
+        assert_eq!(EXITS.load(Ordering::SeqCst), 1);
+        // Nothing the loop held keeps the scope alive
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+        drop(core);
+        assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_backend_fd() {
+        use mio;
+        use mio::unix::EventedFd;
+        use node::RunMode;
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        static N: AtomicUsize = AtomicUsize::new(0);
+        // The caller's own event source on the Poll which the loop is given
+        let poll = mio::Poll::new().unwrap();
+        let own = mio::net::UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let own_addr = own.local_addr().unwrap();
+        poll.register(&own, mio::Token(1), mio::Ready::readable(), mio::PollOpt::edge()).unwrap();
+        let (core, _) = module().with_poll(poll, |ev| {
+            assert_eq!(ev.token(), mio::Token(1));
+            N.fetch_add(100, Ordering::SeqCst);
+        }).create((), |s| {
+            set_timeout(s, |_,_|{ N.fetch_add(1, Ordering::SeqCst); }, 20);
+            let r = s.remote();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                let udp = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+                udp.send_to(b"hi", own_addr).unwrap();
+                r.post(|_| { N.fetch_add(10, Ordering::SeqCst); });
+            });
+        });
+        // Another application's loop which has the noders loop nested in it
+        let outer = mio::Poll::new().unwrap();
+        outer.register(&EventedFd(&core.backend_fd()), mio::Token(0), mio::Ready::readable(),
+            mio::PollOpt::level()).unwrap();
+        let mut events = mio::Events::with_capacity(8);
+        while core.run(RunMode::NoWait) {
+            outer.poll(&mut events, core.backend_timeout()).unwrap();
+        }
+        assert_eq!(N.load(Ordering::SeqCst), 111);
+    }
+
+    #[test]
+    fn test_closures() {
+        let greeting = String::from("hello");
+        let n = module().run(rec!{
+            msg: String::new(),
+            x: 0
+        }, move |s| {
+            // Captured rather than passed in through the scope value
+            s.msg = greeting;
+            let limit = 3;
+            let ss = s.with_scope(rec!{ count: 0 }, move |ss| {
+                assert_eq!(ss.count, 5);
+                set_interval(ss, move |ss,t|{
+                    ss.count += 1;
+                    if ss.count == 5 + limit {
+                        ss.p().x = ss.count;
+                        clear_timeout(ss, t);
+                    }
+                }, 5);
+            });
+            // The handle works before the scope's function has run
+            ss.borrow_mut().count = 5;
+            set_timeout(&*ss.borrow(), |ss,_|{ assert_eq!(ss.p().msg, "hello"); }, 1);
+            s.msg.len()
+        });
+        assert_eq!(n, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "borrow")]
+    fn test_p_double_borrow() {
+        module().run(rec!{ n: 0 }, |s| {
+            let ss = s.with_scope((), |_|{});
+            // The parent is borrowed while its own code runs
+            ss.borrow().p().n += 1;
+        });
+    }
+
+    #[test]
+    fn test_ancestors() {
+        module().run(rec!{ n: 0 }, |s| {
+            let ss = s.with_scope(rec!{ m: 0 }, |ss| {
+                ss.with_scope((), |sss| {
+                    sss.p().p().n += 1;
+                    sss.p().m += 1;
+                    sss.root().borrow_mut().n += 10;
+                    // Shared borrows can overlap
+                    let (a, b) = (sss.p_ref(), sss.p_ref());
+                    assert_eq!(a.m + b.m, 2);
+                    assert_eq!(a.p_ref().n, 11);
+                    drop((a, b));
+                    // While a parent is mutably borrowed try_p() fails instead of panicking
+                    let root = sss.root();
+                    let _guard = root.borrow_mut();
+                    assert!(sss.p().try_p().is_none());
+                    assert!(sss.p().try_p_ref().is_none());
+                });
+            });
+            assert!(ss.borrow().try_p().is_none());
+        });
+    }
+
+    #[test]
+    fn test_scope_close() {
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        static CLOSED: AtomicUsize = AtomicUsize::new(0);
+        static DISPOSED: AtomicUsize = AtomicUsize::new(0);
+        module().run((), |s| {
+            let ss = s.with_scope((), |ss| {
+                set_interval(ss, |_,_|{}, 5);
+                let sock = create_socket("udp4").unwrap().bind((0, "127.0.0.1")).unwrap();
+                sock.on_message(ss, |_,_|{});
+                sock.on_close(ss, |_|{ CLOSED.fetch_add(1, Ordering::SeqCst); });
+                ss.with_scope((), |sss| {
+                    set_interval(sss, |_,_|{}, 5);
+                    sss.on_dispose(||{ DISPOSED.fetch_add(1, Ordering::SeqCst); });
+                    let t = sss.on_dispose(||{ panic!("forgotten"); });
+                    sss.forget_dispose(t);
+                });
+            });
+            // Without this the intervals and the socket would keep the loop going forever
+            set_timeout(s, move |_,_|{
+                let ss = ss.borrow();
+                ss.close();
+                assert!(ss.is_closed());
+                set_timeout(&*ss, |_,_|{ panic!("scope is closed"); }, 1);
+            }, 30);
+        });
+        assert_eq!(CLOSED.load(Ordering::SeqCst), 1);
+        assert_eq!(DISPOSED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_scope_leak() {
+        module().run((), |s| {
+            // Timers cleared from anywhere are no longer in their scope
+            for _ in 0..100 {
+                let t = set_timeout(s, |_,_|{}, 1000);
+                assert!(s.core().deregister_event(&t).unwrap());
+                let t = set_timeout(s, |_,_|{}, 1000);
+                s.with_scope((), move |ss|{ assert!(clear_timeout(ss, t)); ss.close(); });
+            }
+            set_timeout(s, |s,_|{ assert!(s.resources().is_empty()); }, 10);
+        });
+        module().run((), |s| {
+            // Child scopes and sockets which have gone are no longer in the parent
+            for _ in 0..100 {
+                let sock = create_socket("udp4").unwrap().bind((0, "127.0.0.1")).unwrap();
+                sock.on_message(s, |_,_|{});
+                sock.close();
+                s.with_scope((), |ss|{ ss.close(); });
+                s.with_scope((), |_|{});
+            }
+            assert_eq!(s.resources().len(), 200);
+            set_timeout(s, |s,_|{ assert!(s.resources().is_empty()); }, 10);
+        });
+    }
+
+    #[test]
+    fn test_abort() {
+        use abort::{ AbortController, AbortSignal, is_aborted };
+        use time::set_timeout_signal;
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        static ABORTED: AtomicUsize = AtomicUsize::new(0);
+        static SENT: AtomicUsize = AtomicUsize::new(0);
+        fn aborted<L,T>(_: &mut L, r: io::Result<T>) {
+            assert!(is_aborted(r.as_ref().err().unwrap()));
+            ABORTED.fetch_add(1, Ordering::SeqCst);
+        }
+        module().run((), |s| {
+            let ac = AbortController::new();
+            set_timeout_signal(s, &ac.signal(), aborted, 5000);
+            set_timeout(s, move |_,_|{ ac.abort() }, 10);
+
+            let ac2 = AbortController::new();
+            let any = AbortSignal::any(&[&ac2.signal(), &AbortSignal::timeout(s, 20)]);
+            // Doesn't keep the loop going
+            AbortSignal::timeout(s, 60_000);
+            set_timeout_signal(s, &any, aborted, 5000);
+
+            // Combined signals which have fired are gone from their sources
+            let long = AbortController::new();
+            for _ in 0..100 {
+                let ac = AbortController::new();
+                let any = AbortSignal::any(&[&long.signal(), &ac.signal()]);
+                ac.abort();
+                assert!(any.aborted());
+            }
+            assert_eq!(long.signal().len(), 0);
+
+            // Aborted timers are gone from their scope
+            s.with_scope((), |ss|{
+                let ac = AbortController::new();
+                for _ in 0..1000 { set_timeout_signal(ss, &ac.signal(), |_,_|{}, 5000); }
+                ac.abort();
+                assert!(ss.resources().is_empty());
+                ss.close();
+            });
+
+            let sock = create_socket("udp4").unwrap().bind((0, "127.0.0.1")).unwrap();
+            let ac3 = AbortController::new();
+            ac3.abort();
+            sock.send_to_signal(s, "x", (6672, "127.0.0.1"), &ac3.signal(), aborted);
+            sock.send_to_signal(s, "x", (6672, "127.0.0.1"), &ac2.signal(), |_,r|{
+                assert!(r.is_ok());
+                SENT.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+        assert_eq!(ABORTED.load(Ordering::SeqCst), 3);
+        assert_eq!(SENT.load(Ordering::SeqCst), 1);
+    }
+
+    rec!{
+        #[derive(Debug, Default)]
+        struct Named { n: u32 = 7, s: Option<String> = None }
+    }
+    fn takes_named(n: &Named) -> u32 { n.n }
+
+    #[test]
+    fn test_rec() {
+        let r = rec!{
+            #[derive(Debug, Default)]
+            a: 1, b: 2, c: 3, d: 4, e: 5, f: 6, g: 7, h: 8, i: 9, j: 10, k: 11, l: 12, m: 13,
+            none: Option<u8> = None,
+            unit: ()
+        };
+        assert_eq!(r.m + r.a, 14);
+        assert!(r.none.is_none());
+        assert!(format!("{:?}", r).contains("m: 13"));
+        let named = [Named::new(), Named::default()];
+        assert_eq!(takes_named(&named[0]), 7);
+        assert_eq!(named[1].n, 0);
+        assert!(named[0].s.is_none());
+        module().run(Named::new(), |s| {
+            set_timeout(s, |s,_|{ s.n += 1; assert_eq!(s.n, 8); }, 1);
+        });
+    }
+
+    #[::noders::main(threads = 2, state = Named { n: 1, s: None })]
+    fn attr_main(s: &mut ::node::Scope<Named>) -> usize {
+        set_timeout(s, |s,_|{ s.n += 1; }, 1);
+        s.core().threadpool().size()
+    }
+
+    #[test]
+    fn test_attr_main() { assert_eq!(attr_main(), 2); }
+
+    #[::noders::test(timeout = 1000, state = Named::new())]
+    fn test_attr_test(s: &mut ::node::Scope<Named>) {
+        set_timeout(s, |s,_|{ assert_eq!(s.n, 7); }, 1);
+    }
+
+    #[test]
+    fn test_active_handles() {
+        use node::{ ActiveHandle, HandleKind, RunMode };
+        use callback::Callback;
+        let (core, (t, i, cb)) = module().create((), |s| {
+            let sock = create_socket("udp4").unwrap().bind((0, "127.0.0.1")).unwrap();
+            sock.on_message(s, |_,_|{});
+            let cb: Callback<()> = s.cb(|_,_|{});
+            assert!(cb.label(s.core(), "held elsewhere"));
+            let u = set_timeout(s, |_,_|{}, 500);
+            assert!(s.core().unref_timer(&u));
+            (set_timeout(s, |_,_|{}, 1000), set_interval(s, |_,_|{}, 1000), cb)
+        });
+        assert!(core.label(&i, "tick"));
+        let h = core.active_handles();
+        let kinds = h.iter().map(|h| h.kind).collect::<Vec<_>>();
+        assert_eq!(kinds.iter().filter(|k| **k != HandleKind::Callback).count(), 3);
+        assert_eq!(kinds[..3], [HandleKind::Event, HandleKind::Timeout, HandleKind::Interval]);
+        assert!(h[0].name.contains("UdpSocket"));
+        assert_eq!((h[1].token, h[2].token), (t, i));
+        assert_eq!(h[2].to_string().lines().next().unwrap(),
+            format!("Interval {} 1000ms (tick)", i.0));
+        let labeled = |h: &[ActiveHandle]| h.iter()
+            .filter(|h| h.label.as_deref() == Some("held elsewhere")).count();
+        assert_eq!(labeled(&h), 1);
+        assert!(h[3..].iter().all(|h| h.kind == HandleKind::Callback));
+        drop(cb);
+        core.run(RunMode::NoWait);
+        assert_eq!(labeled(&core.active_handles()), 0);
+        core.deregister_event(&t).unwrap();
+        assert_eq!(core.active_handles().iter().filter(|h| h.kind != HandleKind::Callback).count(), 2);
+    }
+
+    #[test]
+    fn test_perf() {
+        use perf::*;
+        use std::sync::atomic::{ AtomicBool, Ordering };
+        let mut h = Histogram::new();
+        for ms in 1..=100 { h.record(Duration::from_millis(ms)); }
+        assert_eq!(h.count(), 100);
+        assert_eq!((h.min(), h.max()), (Duration::from_millis(1), Duration::from_millis(100)));
+        let p50 = h.percentile(50.0).as_micros();
+        assert!((49_900..=50_000).contains(&p50), "{}", p50);
+
+        static CHECKED: AtomicBool = AtomicBool::new(false);
+        // The monitor's interval doesn't keep the loop going
+        module().run((), |s| { s.with_scope(rec!{ slow: 0, delay: None, hook: None }, |s| {
+            s.delay = Some(monitor_event_loop_delay(s, 5));
+            s.hook = Some(on_slow_callback(s, 20, |s, d|{
+                assert!(d >= Duration::from_millis(20));
+                s.slow += 1;
+            }));
+            set_timeout(s, |_,_|{ thread::sleep(Duration::from_millis(30)); }, 10);
+            set_timeout(s, |s,_|{
+                let elu = event_loop_utilization(s);
+                assert!(elu.active >= Duration::from_millis(30) && elu.utilization() > 0.0);
+                assert_eq!(s.slow, 1);
+                let h = s.delay.as_ref().unwrap().histogram();
+                assert!(h.count() > 0 && h.max() >= Duration::from_millis(20));
+                // Gone with its handle
+                s.hook = None;
+                set_timeout(s, |_,_|{ thread::sleep(Duration::from_millis(30)); }, 1);
+                set_timeout(s, |s,_|{
+                    assert_eq!(s.slow, 1);
+                    CHECKED.store(true, Ordering::SeqCst);
+                }, 40);
+            }, 60);
+        }); });
+        assert!(CHECKED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_async_hooks() {
+        use async_hooks::*;
+        use threadpool::queue_work;
+        use std::cell::RefCell;
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+        static SEEN: AtomicUsize = AtomicUsize::new(0);
+        fn seen(als: &AsyncLocalStorage<&'static str>) {
+            assert_eq!(*als.get().unwrap(), "req1");
+            SEEN.fetch_add(1, Ordering::SeqCst);
+        }
+        struct Log(Rc<RefCell<Vec<(&'static str, u64)>>>);
+        impl AsyncHook for Log {
+            fn init(&self, id: u64, _: u64) { self.0.borrow_mut().push(("init", id)); }
+            fn before(&self, id: u64) { self.0.borrow_mut().push(("before", id)); }
+            fn after(&self, id: u64) { self.0.borrow_mut().push(("after", id)); }
+            fn destroy(&self, id: u64) { self.0.borrow_mut().push(("destroy", id)); }
+        }
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let hook = create_hook(Log(log.clone()));
+        let als = AsyncLocalStorage::<&'static str>::new();
+        let als2 = als.clone();
+        module().run((), move |s| {
+            let outer = execution_async_id();
+            set_timeout(s, move |s,_|{
+                assert_eq!(trigger_async_id(), outer);
+                assert!(als.get().is_none());
+                als.run("req1", || {
+                    let als_ = als.clone();
+                    set_timeout(s, move |_,_|{ seen(&als_) }, 1);
+                    let als_ = als.clone();
+                    queue_work(s, || 1, move |_,_|{ seen(&als_) });
+                    let sock = create_socket("udp4").unwrap().bind((0, "127.0.0.1")).unwrap();
+                    let als_ = als.clone();
+                    sock.send_to(s, "x", (6672, "127.0.0.1"), move |_,_|{
+                        seen(&als_);
+                        als_.exit(|| assert!(als_.get().is_none()));
+                    });
+                });
+                assert!(als.get().is_none());
+            }, 1);
+        });
+        assert!(als2.get().is_none());
+        assert_eq!(SEEN.load(Ordering::SeqCst), 3);
+        drop(hook);
+        let log = log.borrow();
+        // The first timer's callback: made, run, and freed
+        let id = log[0].1;
+        for ev in &["init", "before", "after", "destroy"] {
+            assert!(log.contains(&(*ev, id)), "{} {}", ev, id);
+        }
+        let n = |ev| log.iter().filter(|x| x.0 == ev).count();
+        assert_eq!(n("before"), n("after"));
+        assert_eq!(n("init"), n("destroy"));
+
+        // A callback which panics doesn't leave its context behind
+        let als = AsyncLocalStorage::<&'static str>::new();
+        als.run("outer", || {
+            let als_ = als.clone();
+            let r = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+                module().run((), move |s| {
+                    als_.run("inner", || { set_timeout(s, |_,_|{ panic!("boom"); }, 1); });
+                })
+            }));
+            assert!(r.is_err());
+            assert_eq!(execution_async_id(), 1);
+            assert_eq!(*als.get().unwrap(), "outer");
+        });
+    }
+
+    // Would take a minute on the system clock
+    #[::noders::test(clock = "virtual", timeout = 1000)]
+    fn test_virtual_clock(s: &mut ::node::Scope<()>) {
+        let t0 = ::std::time::Instant::now();
+        set_timeout(s, |_,_|{ panic!("scope was closed first"); }, 60_001);
+        let t = set_interval(s, |_,_|{}, 10_000);
+        set_timeout(s, move |s,_|{
+            clear_timeout(s, t);
+            s.close();
+            assert!(t0.elapsed() < Duration::from_secs(1));
+        }, 60_000);
+    }
+
+    #[::noders::test(timeout = 50)]
+    #[should_panic(expected = "still busy")]
+    fn test_attr_test_timeout(s: &mut ::node::Scope<()>) {
+        set_interval(s, |_,_|{}, 5);
+    }
+
     #[test]
     fn test_main() {
         println!("hi");
@@ -1,23 +1,17 @@
+/// Build a record, an anonymous struct whose field types are inferred from their values, for
+/// use as a scope. A field's type can be given where inference needs help, and attributes such
+/// as derives are applied to the struct.
+///
+/// ```ignore
+/// rec!{ #[derive(Debug, Default)] i: 0, name: Option<String> = None }
+/// ```
+///
+/// To name the type, declare it as an item where every field has a type, `new()` is generated
+/// from the initial values when all the fields have one.
+///
+/// ```ignore
+/// rec!{ #[derive(Debug)] pub struct Ctx { i: u32 = 0, name: Option<String> = None } }
+/// ```
 #[macro_export] macro_rules! rec {
-    (@mkstruct (
-        [$firstlet:ident, $($letter:ident),*],
-        { }
-    ) -> ([$($letter_out:ident),*], {$($id_out:ident : $val_out:expr),*})) => {
-        {
-            struct Rec<$($letter_out,)*> { $($id_out : $letter_out),* }
-            Rec { $($id_out : $val_out),* }
-        }
-    };
-    (@mkstruct (
-        [ $letter0:ident, $($letter:ident),* ],
-        { $id0:ident : $val0:expr $(,$id:ident : $val:expr)* }
-    ) -> ([ $($letter_out:ident),* ], { $($id_out:ident : $val_out:expr),* })) => {
-        rec!(@mkstruct([$($letter),*], { $($id : $val),* }) -> (
-            [$letter0 $(,$letter_out)*],
-            { $id0 : $val0 $(,$id_out : $val_out)* }
-        ));
-    };
-    { $($id:ident : $val:expr),+ } => {
-        rec!(@mkstruct([A,B,C,D,E,F,G,H,I,J,K,L], { $($id : $val),* } ) -> ([],{}) );
-    }
-}
\ No newline at end of file
+    ($($t:tt)*) => { $crate::record!{ $($t)* } }
+}
@@ -6,19 +6,31 @@ extern crate mio_extras;
 use super::Token;
 
 use callback::*;
+use threadpool::ThreadPool;
+use remote::LoopRemote;
+use async_hooks;
 
-use std::cell::RefMut;
-use std::cell::RefCell;
+use std::cell::{ Ref, RefMut };
+use std::cell::{ Cell, RefCell };
 use std::rc::{ Rc, Weak };
 use std::fmt;
 use std::io;
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 use std::collections::BTreeMap;
-use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering as AtomicOrdering };
+use std::panic;
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::io::{ AsRawFd, RawFd };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
 use std::cmp::Ordering;
 use std::ops::{ Deref, DerefMut };
 use mio_extras::channel::{ Sender, Receiver };
 use std::io::ErrorKind;
+use std::any::Any;
+use std::backtrace::{ Backtrace, BacktraceStatus };
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 // Event loop core
@@ -28,7 +40,11 @@ struct TimerCb {
     interval: bool,
     cb: Callback<Token>,
     millis: u64,
-    id: Token
+    id: Token,
+    bt: Option<Arc<Backtrace>>,
+    label: Option<String>,
+    // Doesn't keep the loop alive
+    unref: bool
 }
 impl fmt::Debug for TimerCb {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -39,7 +55,10 @@ impl fmt::Debug for TimerCb {
 struct EventHandler {
     handler: Callback<()>,
     token: Token,
-    ev: Rc<mio::Evented>
+    ev: Rc<dyn mio::Evented>,
+    name: &'static str,
+    bt: Option<Arc<Backtrace>>,
+    label: Option<String>
 }
 impl fmt::Debug for EventHandler {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -47,17 +66,90 @@ impl fmt::Debug for EventHandler {
     }
 }
 
+// Only in debug builds, and only if RUST_BACKTRACE or RUST_LIB_BACKTRACE asks for them
+fn backtrace() -> Option<Arc<Backtrace>> {
+    if !cfg!(debug_assertions) { return None; }
+    let bt = Backtrace::capture();
+    if bt.status() == BacktraceStatus::Captured { Some(Arc::new(bt)) } else { None }
+}
+
+// A callback which keeps the loop alive
+struct CallbackInfo {
+    bt: Option<Arc<Backtrace>>,
+    label: Option<String>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandleKind { Event, Timeout, Interval, Callback }
+
+/// Something registered with a loop which keeps it alive, see Core::active_handles().
+#[derive(Clone, Debug)]
+pub struct ActiveHandle {
+    /// For a Callback, its id
+    pub token: Token,
+    pub kind: HandleKind,
+    /// The type of an event source, or the delay of a timer
+    pub name: String,
+    /// Set with Core::label() or Callback::label()
+    pub label: Option<String>,
+    /// Where it was registered, in debug builds with RUST_BACKTRACE set
+    pub backtrace: Option<Arc<Backtrace>>
+}
+impl fmt::Display for ActiveHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {} {}", self.kind, self.token.0, self.name)?;
+        if let Some(l) = self.label.as_ref() { write!(f, " ({})", l)?; }
+        if let Some(bt) = self.backtrace.as_ref() { write!(f, "\n{}", bt)?; }
+        Ok(())
+    }
+}
+
+// Gets the events for the tokens which a caller registered with their own Poll
+type EventHook = Box<dyn FnMut(mio::Event) + Send>;
+
+/// What a loop's timers are measured against, see ModuleCfg::clock()
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Clock {
+    System,
+    /// Stands still while the loop has something to do and jumps straight to the next timer
+    /// when it would wait for it, so tests with long timers don't take long. Work on other
+    /// threads (the thread pool, workers, remotes) doesn't stop it jumping.
+    Virtual
+}
+
 struct CorePvt {
     handlers: HashMap<Token, EventHandler>,
     poll: mio::Poll,
+    on_foreign_event: Option<EventHook>,
     next_timeouts: BTreeMap<Duration, Vec<TimerCb>>,
     event_count: usize,
     next_token: usize,
     now: Duration,
+    clock: Clock,
     callback_receiver: Receiver<CallbackEv>,
 
     next_callback_id: i32,
-    callbacks_this_cycle: Vec<(i32, CallbackImpl)>
+    callbacks_this_cycle: Vec<(i32, CallbackImpl)>,
+    // The registered callbacks which keep the loop alive, for active_handles()
+    callback_info: HashMap<i32, CallbackInfo>,
+    // The scopes which own timers, see Core::own()
+    owners: HashMap<Token, Weak<Resources>>,
+
+    threadpool_size: usize,
+    // Started on first use
+    threadpool: Option<Rc<ThreadPool>>,
+
+    stop: bool,
+    on_before_exit: Vec<Box<dyn FnMut()>>,
+    on_exit: Vec<Box<dyn FnOnce()>>,
+
+    created: Instant,
+    // Time spent waiting in poll
+    idle: Duration,
+    slow_callback: Duration,
+    // Changes whenever the hook is set or cleared
+    slow_callback_id: usize,
+    on_slow_callback: Option<Box<dyn FnMut(Duration)>>
 }
 
 impl CorePvt {
@@ -65,34 +157,34 @@ impl CorePvt {
     {
         let mut dur = None;
         let mut remove = Vec::new();
-        self.now = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Err(_e) => {
-                debug!("Failed to get system time");
-                return dur;
-            }
-            Ok(x) => x
-        };
+        if self.clock == Clock::System {
+            self.now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                Err(_e) => {
+                    debug!("Failed to get system time");
+                    return dur;
+                }
+                Ok(x) => x
+            };
+        }
         let now = self.now;
         for (time, _to) in self.next_timeouts.iter() {
             match time.cmp(&now) {
                 Ordering::Equal | Ordering::Less => {
-                    remove.push(time.clone());
+                    remove.push(*time);
                 }
                 Ordering::Greater => {
-                    dur = Some(time.clone() - self.now);
+                    dur = Some(*time - self.now);
                     break;
                 }
             }
         }
         for time in remove {
-            let x = self.next_timeouts.remove(&time);
-            for xx in x {
-                for el in xx {
-                    el.cb.call(el.id);
-                    if el.interval {
-                        let d = now + Duration::from_millis(el.millis);
-                        self._schedule_timeout(el, d);
-                    }
+            for el in self.next_timeouts.remove(&time).unwrap_or_default() {
+                el.cb.call(el.id);
+                if !el.interval { self.owners.remove(&el.id); }
+                if el.interval {
+                    let d = now + Duration::from_millis(el.millis);
+                    self._schedule_timeout(el, d);
                 }
             }
         }
@@ -100,13 +192,7 @@ impl CorePvt {
     }
     fn _schedule_timeout(&mut self, t: TimerCb, when: Duration)
     {
-        match {match self.next_timeouts.get_mut(&when) {
-            Some(x) => { x.push(t); None },
-            None => Some(t)
-        }} {
-            Some(x) => { self.next_timeouts.insert(when, vec![x]); }
-            None => ()
-        }
+        self.next_timeouts.entry(when).or_default().push(t);
     }
 
     //////////////////////////
@@ -120,7 +206,9 @@ impl CorePvt {
         where E: mio::Evented, E: 'static
     {
         let token = Token(self.next_token);
-        let eh = EventHandler { handler, token, ev: ev.clone() };
+        let name = std::any::type_name::<E>();
+        let eh = EventHandler { handler, token, ev: ev.clone(), name, bt: backtrace(),
+            label: None };
         self.handlers.insert(token, eh);
         self.next_token += 1;
         self.poll.register(&*ev, token.into(), ready, pollopt)?;
@@ -141,25 +229,17 @@ impl CorePvt {
             },
             None => {
                 // No event, try it as a timeout
-                let mut remove_time = None;
+                let mut found = None;
                 for (time, tos) in self.next_timeouts.iter_mut() {
-                    match {
-                        match tos.iter().enumerate().find(|&x| x.1.id == *token) {
-                            Some((i, _elem)) => Some(i),
-                            None => None
-                        }
-                    } {
-                        Some(i) => {
-                            tos.remove(i);
-                            if tos.is_empty() { remove_time = Some(time.clone()); }
-                            break;
-                        }
-                        None => ()
-                    };
+                    if let Some(i) = tos.iter().position(|x| x.id == *token) {
+                        tos.remove(i);
+                        found = Some((*time, tos.is_empty()));
+                        break;
+                    }
                 }
-                match remove_time {
-                    Some(rt) => {
-                        self.next_timeouts.remove(&rt);
+                match found {
+                    Some((rt, empty)) => {
+                        if empty { self.next_timeouts.remove(&rt); }
                         Ok(true)
                     },
                     None => Ok(false)
@@ -171,7 +251,8 @@ impl CorePvt {
     fn set_timeout(&mut self, cb: Callback<Token>, millis: u64, interval: bool) -> Token {
         let id = Token(self.next_token);
         self.next_token += 1;
-        let tcb = TimerCb { cb: cb, interval, millis, id: id.clone() };
+        let tcb = TimerCb { cb, interval, millis, id, bt: backtrace(), label: None,
+            unref: false };
         debug!("_set_timeout in {:?}", Duration::from_millis(millis));
         let d = self.now + Duration::from_millis(millis);
         self._schedule_timeout(tcb, d);
@@ -182,15 +263,33 @@ impl CorePvt {
         self.next_callback_id
     }
     fn register_callback(&mut self, id: i32, cbi: CallbackImpl) {
+        if cbi.keepalive { self.callback_info.insert(id, CallbackInfo { bt: backtrace(), label: None }); }
         self.callbacks_this_cycle.push((id, cbi));
         self.next_callback_id += 1;
     }
 }
 
+/// Keeps a loop alive until it is dropped, on any thread.
+pub struct KeepAlive {
+    count: Arc<AtomicUsize>,
+    sender: Sender<CallbackEv>
+}
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, AtomicOrdering::SeqCst);
+        let _ = self.sender.send(CallbackEv::Wake);
+    }
+}
+
 #[derive(Clone)]
 pub struct Core {
     wp: Rc<RefCell<CorePvt>>,
-    pub callback_sender: Sender<CallbackEv>
+    keepalive: Arc<AtomicUsize>,
+    // Taken while the loop is running
+    runner: Rc<RefCell<Option<Runner>>>,
+    pub callback_sender: Sender<CallbackEv>,
+    pub local_queue: LocalQueue,
+    pub loop_id: usize
 }
 impl Core {
     pub fn register_event<E>(
@@ -205,14 +304,29 @@ impl Core {
         debug!("Register {:?}", &out);
         out
     }
+    /// Also forgets the disposer of the scope which owns it, if any
     pub fn deregister_event(&self, token: &Token) -> io::Result<bool> {
         let out = self.wp.borrow_mut().deregister_event(token);
         debug!("Deregister {} {:?}", token.0, &out);
+        let owner = self.wp.borrow_mut().owners.remove(token);
+        if let Some(res) = owner.and_then(|r| r.upgrade()) { res.forget(*token); }
         out
     }
+    /// Make a timer or event belong to a scope, it is deregistered when the scope is disposed
+    pub fn own(&self, t: Token, res: &Rc<Resources>) {
+        let c = self.clone();
+        res.add(t, Box::new(move ||{ let _ = c.deregister_event(&t); }));
+        if !res.is_closed() { self.wp.borrow_mut().owners.insert(t, Rc::downgrade(res)); }
+    }
     pub fn set_timeout(&self, cb: Callback<Token>, millis: u64, interval: bool) -> Token {
         self.wp.borrow_mut().set_timeout(cb, millis, interval)
     }
+    /// A token which nothing else in this loop uses
+    pub fn new_token(&self) -> Token {
+        let mut w = self.wp.borrow_mut();
+        w.next_token += 1;
+        Token(w.next_token - 1)
+    }
 
     pub fn next_callback_id(&self) -> i32 {
         self.wp.borrow_mut().next_callback_id()
@@ -220,16 +334,211 @@ impl Core {
     pub fn register_callback(&self, id: i32, cbi: CallbackImpl) {
         self.wp.borrow_mut().register_callback(id, cbi)
     }
+
+    pub fn threadpool(&self) -> Rc<ThreadPool> {
+        let mut w = self.wp.borrow_mut();
+        let size = w.threadpool_size;
+        w.threadpool.get_or_insert_with(|| Rc::new(ThreadPool::new(size))).clone()
+    }
+    pub fn threadpool_size(&self) -> usize { self.wp.borrow().threadpool_size }
+    pub fn clock(&self) -> Clock { self.wp.borrow().clock }
+
+    /// Run the loop, returns true if it still has something to do. Loops started with
+    /// ModuleCfg::run() are already run, this is for loops made with ModuleCfg::create().
+    /// In any mode, once it returns false the before exit and exit hooks have been called and
+    /// whatever the loop still held has been dropped.
+    pub fn run(&self, mode: RunMode) -> bool {
+        let r = self.runner.borrow_mut().take();
+        let mut r = match r {
+            Some(r) => r,
+            None => { warn!("run() Loop is already running"); return true; }
+        };
+        register_local_queue(self.loop_id, &self.local_queue);
+        let alive = run_loop(self, &mut r, mode);
+        *self.runner.borrow_mut() = Some(r);
+        if !alive {
+            exit_hooks(self);
+            teardown(self);
+        }
+        alive
+    }
+    /// The fd which becomes readable when the loop has events, so that another loop can poll
+    /// it and call run(RunMode::NoWait) when it's ready.
+    #[cfg(unix)]
+    pub fn backend_fd(&self) -> RawFd { self.wp.borrow().poll.as_raw_fd() }
+    /// How long an outer loop may wait on backend_fd() before calling run(), None if there is
+    /// no timer so it may wait until the fd is ready.
+    pub fn backend_timeout(&self) -> Option<Duration> {
+        let w = self.wp.borrow();
+        if !self.local_queue.borrow().is_empty() || !w.callbacks_this_cycle.is_empty() {
+            return Some(Duration::from_millis(0));
+        }
+        let now = match w.clock {
+            Clock::System => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(w.now),
+            // Gets to the next timer on the next run()
+            Clock::Virtual => Duration::MAX
+        };
+        w.next_timeouts.keys().next().map(|t| {
+            if *t > now { *t - now } else { Duration::from_millis(0) }
+        })
+    }
+    /// Make run() return as soon as the callback which is running now is done.
+    pub fn stop(&self) { self.wp.borrow_mut().stop = true; }
+    /// Whether the loop has anything left which could make it do something.
+    pub fn is_alive(&self) -> bool {
+        if !self.local_queue.borrow().is_empty() { return true; }
+        match self.runner.borrow().as_ref() {
+            Some(r) => _has_work(self, &self.wp.borrow(), r),
+            None => true
+        }
+    }
+
+    /// The events, timers and callbacks which are keeping the loop alive, for finding out why
+    /// it doesn't exit. The callbacks of the events and timers aren't listed separately, nor are
+    /// KeepAlives or unref timers.
+    pub fn active_handles(&self) -> Vec<ActiveHandle> {
+        let w = self.wp.borrow();
+        let mut owned = HashSet::new();
+        let mut out: Vec<ActiveHandle> = w.handlers.values().map(|eh| {
+            owned.insert(eh.handler.id());
+            ActiveHandle {
+                token: eh.token,
+                kind: HandleKind::Event,
+                name: eh.name.to_string(),
+                label: eh.label.clone(),
+                backtrace: eh.bt.clone()
+            }
+        }).collect();
+        for t in w.next_timeouts.values().flatten() {
+            owned.insert(t.cb.id());
+            if t.unref { continue; }
+            out.push(ActiveHandle {
+                token: t.id,
+                kind: if t.interval { HandleKind::Interval } else { HandleKind::Timeout },
+                name: format!("{}ms", t.millis),
+                label: t.label.clone(),
+                backtrace: t.bt.clone()
+            });
+        }
+        for (id, ci) in w.callback_info.iter().filter(|(id, _)| !owned.contains(*id)) {
+            out.push(ActiveHandle {
+                token: Token(*id as usize),
+                kind: HandleKind::Callback,
+                name: String::new(),
+                label: ci.label.clone(),
+                backtrace: ci.bt.clone()
+            });
+        }
+        out.sort_by_key(|h| (h.kind == HandleKind::Callback, h.token));
+        out
+    }
+
+    /// Name an event or timer in active_handles(), returns false if there's none with this token
+    pub fn label(&self, t: &Token, label: &str) -> bool {
+        let mut w = self.wp.borrow_mut();
+        if let Some(eh) = w.handlers.get_mut(t) {
+            eh.label = Some(label.to_string());
+            return true;
+        }
+        match w.next_timeouts.values_mut().flatten().find(|x| x.id == *t) {
+            Some(x) => { x.label = Some(label.to_string()); true },
+            None => false
+        }
+    }
+    pub(crate) fn label_callback(&self, id: i32, label: &str) -> bool {
+        match self.wp.borrow_mut().callback_info.get_mut(&id) {
+            Some(ci) => { ci.label = Some(label.to_string()); true },
+            None => false
+        }
+    }
+
+    /// Stop a timer from keeping the loop alive, like Node's timeout.unref(). Its callback
+    /// should be unref too (see Callback::new_unref()).
+    pub fn unref_timer(&self, t: &Token) -> bool {
+        let mut w = self.wp.borrow_mut();
+        match w.next_timeouts.values_mut().flatten().find(|x| x.id == *t) {
+            Some(x) => { x.unref = true; true },
+            None => false
+        }
+    }
+    /// Time since the loop was made and how much of it was spent waiting for events
+    pub(crate) fn loop_time(&self) -> (Duration, Duration) {
+        let w = self.wp.borrow();
+        (w.created.elapsed(), w.idle)
+    }
+    pub(crate) fn set_slow_callback_hook(&self, threshold: Duration, f: Box<dyn FnMut(Duration)>)
+        -> usize
+    {
+        let mut w = self.wp.borrow_mut();
+        w.slow_callback = threshold;
+        w.slow_callback_id += 1;
+        w.on_slow_callback = Some(f);
+        w.slow_callback_id
+    }
+    // Unless another hook has replaced it since
+    pub(crate) fn clear_slow_callback_hook(&self, id: usize) {
+        let h = {
+            let mut w = self.wp.borrow_mut();
+            if w.slow_callback_id != id { return; }
+            w.slow_callback_id += 1;
+            w.on_slow_callback.take()
+        };
+        drop(h);
+    }
+
+    pub fn keep_alive(&self) -> KeepAlive {
+        self.keepalive.fetch_add(1, AtomicOrdering::SeqCst);
+        KeepAlive { count: self.keepalive.clone(), sender: self.callback_sender.clone() }
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 // Scope
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 
-fn exec<X,Y>(w:&Core, x:X, f:fn(&mut Scope<X>)->Y) -> Y {
+#[derive(Default)]
+struct ResInner {
+    closed: bool,
+    disposers: BTreeMap<Token, Box<dyn FnOnce()>>
+}
+/// What a scope owns (timers, sockets, child scopes), it is all disposed of when the scope is
+/// closed or dropped.
+#[derive(Default)]
+pub struct Resources {
+    inner: RefCell<ResInner>
+}
+impl Resources {
+    /// Call f when the scope is disposed, or now if it already has been.
+    pub fn add(&self, t: Token, f: Box<dyn FnOnce()>) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.closed { drop(inner); f(); return; }
+        inner.disposers.insert(t, f);
+    }
+    /// The resource has gone by itself, nothing to do on dispose
+    pub fn forget(&self, t: Token) {
+        let f = self.inner.borrow_mut().disposers.remove(&t);
+        drop(f);
+    }
+    pub fn is_closed(&self) -> bool { self.inner.borrow().closed }
+    /// Number of things waiting to be disposed of
+    pub fn len(&self) -> usize { self.inner.borrow().disposers.len() }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+    /// Dispose of everything, newest first.
+    pub fn dispose(&self) {
+        let disposers = {
+            let mut inner = self.inner.borrow_mut();
+            inner.closed = true;
+            mem::take(&mut inner.disposers)
+        };
+        for (_, f) in disposers.into_iter().rev() { f(); }
+    }
+}
+
+fn exec<X:'static,Y,F:FnOnce(&mut Scope<X>)->Y>(w:&Core, x:X, f:F) -> Y {
     let s = Rc::new(RefCell::new(Scope {
         a: x,
         s: Weak::new(),
+        res: Rc::new(Resources::default()),
         w: w.clone()
     }));
     s.borrow_mut().s = Rc::downgrade(&s);
@@ -241,19 +550,49 @@ fn exec<X,Y>(w:&Core, x:X, f:fn(&mut Scope<X>)->Y) -> Y {
 pub struct Scope<A> where A: 'static {
     a: A,
     s: Weak<RefCell<Scope<A>>>,
+    res: Rc<Resources>,
     w: Core
 }
 pub struct SubScope<P,A> where P: Loop<P>, A: 'static, P: 'static {
     p: Rc<RefCell<P>>,
+    // Kept so the root can be reached without borrowing the scopes in between
+    root: Rc<RefCell<P::Root>>,
     a: A,
     s: Weak<RefCell<SubScope<P,A>>>,
+    res: Rc<Resources>,
+    // The parent's disposer for this scope, forgotten once this scope has gone
+    in_parent: Cell<Option<(Weak<Resources>, Token)>>,
     w: Core
 }
+impl<A> Drop for Scope<A> {
+    fn drop(&mut self) { self.res.dispose(); }
+}
+impl<P,A> Drop for SubScope<P,A> where P: Loop<P> {
+    fn drop(&mut self) { self.close(); }
+}
 
 impl<P,A> SubScope<P,A> where P: Loop<P> {
-    pub fn p(&mut self) -> RefMut<P> {
+    fn leave_parent(&self) {
+        if let Some((res, t)) = self.in_parent.take() {
+            if let Some(res) = res.upgrade() { res.forget(t); }
+        }
+    }
+    /// The parent scope, this panics if it is already borrowed (for instance if this is
+    /// called from the parent's own code), see try_p().
+    pub fn p(&self) -> RefMut<'_, P> {
         self.p.borrow_mut()
     }
+    /// The parent scope, or None if it is borrowed at the moment.
+    pub fn try_p(&self) -> Option<RefMut<'_, P>> {
+        self.p.try_borrow_mut().ok()
+    }
+    /// Read-only access to the parent, any number of these can be held at once.
+    pub fn p_ref(&self) -> Ref<'_, P> {
+        self.p.borrow()
+    }
+    pub fn try_p_ref(&self) -> Option<Ref<'_, P>> {
+        self.p.try_borrow().ok()
+    }
 }
 
 impl<P,A> Deref for SubScope<P,A> where P: Loop<P> {
@@ -272,31 +611,81 @@ impl<A> DerefMut for Scope<A> {
 }
 
 pub trait Loop<A> where A: 'static + Loop<A>, Self: 'static {
+    /// The outermost scope, the one made by ModuleCfg::run()
+    type Root: 'static + Loop<Self::Root>;
+    fn root(&self) -> Rc<RefCell<A::Root>>;
     fn module(&self) -> ModuleCfg { module().with_loop(self.core().clone()) }
-    fn with_scope<X>(&self, x:X, f:fn(&mut SubScope<A,X>)) {
+    /// Make a child scope, f is called with it on the next tick (when this scope is no longer
+    /// borrowed). The handle which is returned can be used straight away.
+    fn with_scope<X,F>(&self, x:X, f:F) -> Rc<RefCell<SubScope<A,X>>> where
+        F: 'static + FnOnce(&mut SubScope<A,X>)
+    {
         let ss = Rc::new(RefCell::new(SubScope {
             p: self.as_rc(),
+            root: self.root(),
             a: x,
             s: Weak::new(),
+            res: Rc::new(Resources::default()),
+            in_parent: Cell::new(None),
             w: self.core().clone()
         }));
         ss.borrow_mut().s = Rc::downgrade(&ss);
-        self.core().set_timeout(Callback::new(self.core(), (f,ss.clone()), |fw,_y|{
-            fw.0(&mut *fw.1.borrow_mut())
+        // Closing this scope closes the child, without keeping it alive
+        let child = Rc::downgrade(&ss.borrow().res);
+        let t = self.on_dispose(move ||{ if let Some(res) = child.upgrade() { res.dispose(); } });
+        ss.borrow().in_parent.set(Some((Rc::downgrade(self.resources()), t)));
+        self.core().set_timeout(Callback::new_once(self.core(), ss.clone(), |ss,_|{
+            f(&mut *ss.borrow_mut())
         }), 0, false);
+        ss
     }
     fn core(&self) -> &Core;
     fn as_rc(&self) -> Rc<RefCell<A>>;
+    fn resources(&self) -> &Rc<Resources>;
+    /// Call f when this scope is closed or dropped, returns a token for forget_dispose().
+    fn on_dispose<F>(&self, f:F) -> Token where F: 'static + FnOnce() {
+        let t = self.core().new_token();
+        self.resources().add(t, Box::new(f));
+        t
+    }
+    fn forget_dispose(&self, t: Token) { self.resources().forget(t) }
+    /// Cancel the timers, close the sockets and close the child scopes which belong to this
+    /// scope, along with anything else registered with on_dispose().
+    fn close(&self) { self.resources().dispose() }
+    fn is_closed(&self) -> bool { self.resources().is_closed() }
     fn cb<X,F>(&self, f:F) -> Callback<X> where
         X: 'static + Send,
-        F: 'static + Fn(&mut A, X);
+        F: 'static + FnMut(&mut A, X);
+    fn cb_once<X,F>(&self, f:F) -> Callback<X> where
+        X: 'static + Send,
+        F: 'static + FnOnce(&mut A, X)
+    {
+        Callback::new_once(self.core(), self.as_rc(), |l,x|{ f(&mut *l.borrow_mut(), x) })
+    }
+    /// A Send + Sync handle for running closures on this loop from other threads
+    fn remote(&self) -> LoopRemote<A> where Self: Sized { LoopRemote::new(self) }
+    fn local_cb<X,F>(&self, f:F) -> LocalCallback<X> where
+        X: 'static,
+        F: 'static + FnMut(&mut A, X)
+    {
+        LocalCallback::new(self.core(), rec!{ fun: f, l: self.as_rc() }, |ctx,x|{
+            (ctx.fun)(&mut *ctx.l.borrow_mut(), x)
+        })
+    }
 }
 impl<P,A> Loop<SubScope<P,A>> for SubScope<P,A> where P: Loop<P> {
+    type Root = P::Root;
+    fn root(&self) -> Rc<RefCell<P::Root>> { self.root.clone() }
+    fn resources(&self) -> &Rc<Resources> { &self.res }
     fn core(&self) -> &Core { &self.w }
     fn as_rc(&self) -> Rc<RefCell<Self>> { self.s.upgrade().unwrap() }
+    fn close(&self) {
+        self.res.dispose();
+        self.leave_parent();
+    }
     fn cb<X,F>(&self, f:F) -> Callback<X> where
         X: 'static + Send,
-        F: 'static + Fn(&mut SubScope<P,A>, X)
+        F: 'static + FnMut(&mut SubScope<P,A>, X)
     {
         Callback::new(self.core(), rec!{ fun: f, l: self.as_rc() }, |ctx,x|{
             (ctx.fun)(&mut *ctx.l.borrow_mut(), x)
@@ -304,11 +693,14 @@ impl<P,A> Loop<SubScope<P,A>> for SubScope<P,A> where P: Loop<P> {
     }
 }
 impl<A> Loop<Scope<A>> for Scope<A> {
+    type Root = Scope<A>;
+    fn root(&self) -> Rc<RefCell<Scope<A>>> { self.as_rc() }
+    fn resources(&self) -> &Rc<Resources> { &self.res }
     fn core(&self) -> &Core { &self.w }
     fn as_rc(&self) -> Rc<RefCell<Self>> { self.s.upgrade().unwrap() }
     fn cb<X,F>(&self, f:F) -> Callback<X> where
         X: 'static + Send,
-        F: 'static + Fn(&mut Scope<A>, X)
+        F: 'static + FnMut(&mut Scope<A>, X)
     {
         Callback::new(self.core(), rec!{ fun: f, l: self.as_rc() }, |ctx,x|{
             (ctx.fun)(&mut *ctx.l.borrow_mut(), x)
@@ -320,102 +712,310 @@ impl<A> Loop<Scope<A>> for Scope<A> {
 // Loop runner
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 
+static NEXT_LOOP_ID: AtomicUsize = AtomicUsize::new(0);
+
 const CB_RECV_TOKEN: mio::Token = mio::Token(100);
 const FIRST_TOKEN:   usize      = 101;
 
-//self.callback_by_id.insert(id, cbi);
+/// How much Core::run() does before returning
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunMode {
+    /// Until there's nothing left to do or stop() is called
+    Default,
+    /// Poll for events once, waiting if there's nothing to do yet, and dispatch them
+    Once,
+    /// Like Once but never waits
+    NoWait
+}
 
-fn _get_events(
-    _w: &Core,
-    calls: &mut Vec<CallbackEv>,
-    events: &mut mio::Events,
-    callback_by_id: &mut HashMap<i32, CallbackImpl>,
-) -> bool
+/// Called when the loop has run out of things to do, if it schedules more work the loop
+/// carries on and the hook is called again when that is done.
+pub fn on_before_exit<L,F>(l:&L, mut f:F) where
+    L: Loop<L>,
+    F: 'static + FnMut(&mut L)
+{
+    let rc = l.as_rc();
+    l.core().wp.borrow_mut().on_before_exit.push(Box::new(move ||{ f(&mut *rc.borrow_mut()) }));
+}
+/// Called once when the loop has finished
+pub fn on_exit<L,F>(l:&L, f:F) where
+    L: Loop<L>,
+    F: 'static + FnOnce(&mut L)
+{
+    let rc = l.as_rc();
+    l.core().wp.borrow_mut().on_exit.push(Box::new(move ||{ f(&mut *rc.borrow_mut()) }));
+}
+
+struct Runner {
+    events: mio::Events,
+    calls: Vec<CallbackEv>,
+    callback_by_id: HashMap<i32, CallbackImpl>
+}
+
+fn _has_work(c: &Core, w: &CorePvt, r: &Runner) -> bool {
+    // Callbacks are only removed once every handle has gone so if none is left which keeps the
+    // loop alive, nothing which matters can call into this loop any more.
+    w.event_count > 0 || w.next_timeouts.values().flatten().any(|t| !t.unref) ||
+        r.callback_by_id.values().any(|c| c.keepalive) ||
+        w.callbacks_this_cycle.iter().any(|c| c.1.keepalive) ||
+        c.keepalive.load(AtomicOrdering::SeqCst) > 0
+}
+
+fn _get_events(_w: &Core, r: &mut Runner, block: bool) -> bool
 {
     let mut w = _w.wp.borrow_mut();
-    for icb in w.callbacks_this_cycle.drain(..) { callback_by_id.insert(icb.0, icb.1); }
+    for icb in w.callbacks_this_cycle.drain(..) { r.callback_by_id.insert(icb.0, icb.1); }
     let dur = w._do_timeouts();
-    for ev in events.iter() {
+    for ev in r.events.iter() {
         // If we get the CB_RECV_TOKEN, it doesn't matter, we're going to poll anyway
         match w.handlers.get(&Token::from(ev.token())) {
             Some(eh) => { eh.handler.call(()); }
+            None if ev.token() != CB_RECV_TOKEN => {
+                if let Some(h) = w.on_foreign_event.as_mut() { h(ev); }
+            }
             None => ()
-        };
+        }
+    }
+    r.events.clear();
+    while let Ok(cb) = w.callback_receiver.try_recv() { r.calls.push(cb) }
+    if !r.calls.is_empty() || !_w.local_queue.borrow().is_empty() { return true; }
+    if !_has_work(_w, &w, r) { return false; }
+    debug!("Polling for [{:?}], [{}] events [{}] timeouts [{}] callbacks",
+        &dur, w.event_count, w.next_timeouts.len(), r.callback_by_id.len());
+    let virtual_wait = if w.clock == Clock::Virtual { dur } else { None };
+    let dur = if block && virtual_wait.is_none() { dur } else { Some(Duration::from_millis(0)) };
+    let t0 = Instant::now();
+    w.poll.poll(&mut r.events, dur).unwrap();
+    w.idle += t0.elapsed();
+    // Nothing else happened, so on a virtual clock it's time for the next timer
+    if let Some(d) = virtual_wait {
+        if r.events.is_empty() { w.now += d; }
+    }
+    true
+}
+
+fn dispatch_all(w: &Core, r: &mut Runner) {
+    debug!("Dispatching [{}] events", r.calls.len());
+    for ev in r.calls.drain(..) {
+        match ev {
+            CallbackEv::Req(c) => { dispatch(w, &mut r.callback_by_id, c.canary.callback_id, c.x); }
+            CallbackEv::Drop(id) => { forget_callback(w, &mut r.callback_by_id, id); }
+            CallbackEv::Stop => { w.wp.borrow_mut().stop = true; }
+            CallbackEv::Wake => ()
+        }
     }
-    events.clear();
     loop {
-        match w.callback_receiver.try_recv() {
-            Ok(cb) => { calls.push(cb) }
-            _ => break,
+        let ev = w.local_queue.borrow_mut().pop_front();
+        let ev = match ev { Some(ev) => ev, None => break };
+        // The callback might have been created during this cycle
+        for icb in w.wp.borrow_mut().callbacks_this_cycle.drain(..) {
+            r.callback_by_id.insert(icb.0, icb.1);
+        }
+        match ev {
+            LocalEv::Req(c) => { dispatch(w, &mut r.callback_by_id, c.canary.callback_id, c.x); }
+            LocalEv::Drop(id) => { forget_callback(w, &mut r.callback_by_id, id); }
+        }
+    }
+}
+
+fn run_hooks(w: &Core) -> bool {
+    let mut hooks = mem::take(&mut w.wp.borrow_mut().on_before_exit);
+    if hooks.is_empty() { return false; }
+    for h in hooks.iter_mut() { h(); }
+    let mut wp = w.wp.borrow_mut();
+    hooks.append(&mut wp.on_before_exit);
+    wp.on_before_exit = hooks;
+    true
+}
+
+fn exit_hooks(w: &Core) {
+    let hooks = mem::take(&mut w.wp.borrow_mut().on_exit);
+    for h in hooks { h(); }
+}
+
+fn run_loop(w: &Core, r: &mut Runner, mode: RunMode) -> bool {
+    let block = mode != RunMode::NoWait;
+    loop {
+        dispatch_all(w, r);
+        if mem::replace(&mut w.wp.borrow_mut().stop, false) { return true; }
+        if !_get_events(w, r, block) {
+            // The before exit hooks may find more to do
+            if !run_hooks(w) || !_get_events(w, r, block) {
+                return false;
+            }
+        }
+        if mode != RunMode::Default {
+            // Pick up what the poll found
+            _get_events(w, r, false);
+            dispatch_all(w, r);
+            w.wp.borrow_mut().stop = false;
+            return !w.local_queue.borrow().is_empty() || _has_work(w, &w.wp.borrow(), r);
         }
     }
-    if calls.len() > 0 { return true; }
-    callback_by_id.retain(|_k,v|{ v.canary.upgrade().is_some() });
-    if 0 == w.event_count && dur == None && 0 == callback_by_id.len() { return false; }
-    debug!("Polling for [{:?}], [{}] events [{}] timeouts [{}] callbacks",
-        &dur, w.event_count, w.next_timeouts.len(), callback_by_id.len());
-    w.poll.poll(events, dur).unwrap();
-    return true;
 }
 
 use std::thread;
 use std::sync::mpsc;
 
+const DEFAULT_THREADPOOL_SIZE: usize = 4;
+
 pub struct ModuleCfg
 {
     new_thread: bool,
-    with_loop: Option<Core>
+    with_loop: Option<Core>,
+    threadpool_size: usize,
+    clock: Clock,
+    poll: Option<(mio::Poll, EventHook)>
 }
 impl ModuleCfg
 {
     pub fn new_thread(mut self, it: bool) -> Self { self.new_thread = it; self }
     pub fn with_loop(mut self, core: Core) -> Self { self.with_loop = Some(core); self }
+    /// Number of threads used by threadpool::queue_work() in a new loop, default 4.
+    pub fn threadpool_size(mut self, n: usize) -> Self { self.threadpool_size = n; self }
+    /// The clock which a new loop's timers follow, default Clock::System.
+    pub fn clock(mut self, c: Clock) -> Self { self.clock = c; self }
+    /// Build a new loop over the caller's Poll rather than making one. Tokens below 100 are
+    /// left for the caller, events for them are passed to f on the loop's thread. f runs in
+    /// the middle of the loop's own work so it should only note the event, for instance by
+    /// sending it on a channel. It can't be used with with_loop() unless new_thread(true) is
+    /// set too, which makes a new loop.
+    pub fn with_poll<F>(mut self, poll: mio::Poll, f:F) -> Self where
+        F: 'static + Send + FnMut(mio::Event)
+    {
+        self.poll = Some((poll, Box::new(f)));
+        self
+    }
 
-    pub fn run<T,U>(self, t:T, f: fn(&mut Scope<T>)->U) -> U where
+    pub fn run<T,U,F>(self, t:T, f:F) -> U where
         T: Send + 'static,
-        U: Send + 'static
+        U: Send + 'static,
+        F: 'static + Send + FnOnce(&mut Scope<T>)->U
     {
+        if self.new_thread && self.with_loop.is_some() {
+            return match self.spawn(t, f) {
+                Ok((u, _)) => u,
+                Err(e) => panic::resume_unwind(e)
+            };
+        }
         match self.with_loop {
             Some(core) => {
-                if self.new_thread {
-                    let cb: Callback<thread::ThreadId> = Callback::new(&core, (), |_,tid| {
-                        // This callback exists only to prevent the main parent loop from
-                        // shutting down until all child loops have shutdown.
-                        debug!("Thread ended {:?}", tid);
-                    });
-
-                    let (tx, rx) = mpsc::channel();
-                    thread::spawn(move|| {
-                        let tid = thread::current().id();
-                        debug!("Thread started {:?}", tid);
-                        let (w, u) = new_core(t, f, );
-                        tx.send(u).unwrap();
-                        loop_core(w);
-                        cb.call(tid);
-                    });
-                    return rx.recv().unwrap();
-                }
+                // The Poll would be dropped along with the caller's registrations
+                assert!(self.poll.is_none(),
+                    "with_poll() needs a new loop, it can't be used with with_loop() on its own");
                 exec(&core, t, f)
-            }
+            },
             None => {
-                let (w, u) = new_core(t, f);
+                let (w, u) = new_core(t, self.threadpool_size, self.clock, self.poll, f);
                 loop_core(w);
                 u
             }
         }
     }
+    /// Make a new loop on this thread and call f in it, but don't run it. Use Core::run() to
+    /// drive it, for example from another application's main loop.
+    pub fn create<T,U,F>(self, t:T, f:F) -> (Core, U) where
+        T: 'static,
+        F: FnOnce(&mut Scope<T>)->U
+    {
+        new_core(t, self.threadpool_size, self.clock, self.poll, f)
+    }
+    /// Run a new loop on its own thread, returns the result of f once it has returned. If this
+    /// config has a loop (see with_loop()), that loop is kept alive until the new one exits.
+    pub fn spawn<T,U,F>(self, t:T, f:F) -> thread::Result<(U, LoopHandle)> where
+        T: Send + 'static,
+        U: Send + 'static,
+        F: 'static + Send + FnOnce(&mut Scope<T>)->U
+    {
+        let keepalive = self.with_loop.as_ref().map(|c| c.keep_alive());
+        let running = Arc::new(AtomicBool::new(true));
+        let (done_tx, done) = mpsc::channel();
+        let running_ = Running(running.clone(), done_tx);
+        let (tps, clock) = (self.threadpool_size, self.clock);
+        let poll = self.poll;
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move|| {
+            // Both are dropped when the thread ends, even by panicking
+            let _keepalive = keepalive;
+            let _running = running_;
+            debug!("Thread started {:?}", thread::current().id());
+            let (w, u) = new_core(t, tps, clock, poll, f);
+            tx.send((u, w.callback_sender.clone())).unwrap();
+            loop_core(w);
+        });
+        match rx.recv() {
+            Ok((u, stop)) => Ok((u, LoopHandle { thread, running, done, stop })),
+            Err(_) => Err(thread.join().err().unwrap_or_else(|| Box::new("loop did not start")))
+        }
+    }
+    /// Run a new loop on its own thread and wait for it to go idle. If it is still busy after
+    /// millis it is told to stop and this fails with TimedOut, a panic in the loop is passed on.
+    /// A loop which timed out isn't waited for, its thread is detached and carries on until
+    /// the callback which is running returns.
+    pub fn run_timeout<T,F>(self, t:T, millis: u64, f:F) -> io::Result<()> where
+        T: Send + 'static,
+        F: 'static + Send + FnOnce(&mut Scope<T>)
+    {
+        let deadline = Instant::now() + Duration::from_millis(millis);
+        let lh = match self.spawn(t, f) {
+            Ok((_, lh)) => lh,
+            Err(e) => panic::resume_unwind(e)
+        };
+        if !lh.wait_timeout(deadline.saturating_duration_since(Instant::now())) {
+            lh.request_shutdown();
+            return Err(io::Error::new(ErrorKind::TimedOut,
+                format!("loop still busy after {}ms", millis)));
+        }
+        lh.join().unwrap_or_else(|e| panic::resume_unwind(e));
+        Ok(())
+    }
+}
+
+// Dropped when the loop's thread ends, the channel is never sent on
+struct Running(Arc<AtomicBool>, mpsc::Sender<()>);
+impl Drop for Running {
+    fn drop(&mut self) { self.0.store(false, AtomicOrdering::SeqCst); }
 }
+
+/// A loop running on another thread, see ModuleCfg::spawn(). Dropping it detaches the thread.
+pub struct LoopHandle {
+    thread: thread::JoinHandle<()>,
+    running: Arc<AtomicBool>,
+    done: mpsc::Receiver<()>,
+    stop: Sender<CallbackEv>
+}
+impl LoopHandle {
+    /// Wait for the loop to exit, Err holds the panic if it panicked.
+    pub fn join(self) -> thread::Result<()> { self.thread.join() }
+    /// Ask the loop to exit once it has finished what it's doing now.
+    pub fn request_shutdown(&self) { let _ = self.stop.send(CallbackEv::Stop); }
+    pub fn is_running(&self) -> bool { self.running.load(AtomicOrdering::SeqCst) }
+    /// Wait up to d for the loop to exit, returns whether it has.
+    pub fn wait_timeout(&self, d: Duration) -> bool {
+        !matches!(self.done.recv_timeout(d), Err(mpsc::RecvTimeoutError::Timeout))
+    }
+}
+
 pub fn module() -> ModuleCfg {
     ModuleCfg {
         new_thread: false,
-        with_loop: None
+        with_loop: None,
+        threadpool_size: DEFAULT_THREADPOOL_SIZE,
+        clock: Clock::System,
+        poll: None
     }
 }
 
-fn new_core<T,U>(t:T, f: fn(&mut Scope<T>)->U) -> (Core, U)
+pub(crate) fn new_core<T,U,F>(t:T, threadpool_size: usize, clock: Clock,
+    poll: Option<(mio::Poll, EventHook)>, f:F) -> (Core, U) where
+    T: 'static,
+    F: FnOnce(&mut Scope<T>)->U
 {
     let (tx, rx) = mio_extras::channel::channel();
-    let poll = mio::Poll::new().unwrap();
+    let (poll, on_foreign_event) = match poll {
+        Some((p, h)) => (p, Some(h)),
+        None => (mio::Poll::new().unwrap(), None)
+    };
     poll.register(
         &rx,
         CB_RECV_TOKEN,
@@ -424,39 +1024,102 @@ fn new_core<T,U>(t:T, f: fn(&mut Scope<T>)->U) -> (Core, U)
     ).unwrap();
 
     let core = Core {
+        keepalive: Arc::new(AtomicUsize::new(0)),
+        runner: Rc::new(RefCell::new(Some(Runner {
+            events: mio::Events::with_capacity(1024),
+            calls: Vec::new(),
+            callback_by_id: HashMap::new()
+        }))),
         callback_sender: tx,
+        local_queue: Rc::new(RefCell::new(VecDeque::new())),
+        loop_id: NEXT_LOOP_ID.fetch_add(1, AtomicOrdering::Relaxed),
         wp: Rc::new(RefCell::new(CorePvt {
             callback_receiver: rx,
-            poll: poll,
+            poll,
+            on_foreign_event,
             event_count: 0,
             next_token: FIRST_TOKEN,
             handlers: HashMap::new(),
             next_timeouts: BTreeMap::new(),
             now: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            clock,
 
             next_callback_id: 0,
-            callbacks_this_cycle: Vec::new()
+            callbacks_this_cycle: Vec::new(),
+            callback_info: HashMap::new(),
+            owners: HashMap::new(),
+
+            threadpool_size,
+            threadpool: None,
+
+            stop: false,
+            on_before_exit: Vec::new(),
+            on_exit: Vec::new(),
+
+            created: Instant::now(),
+            idle: Duration::from_millis(0),
+            slow_callback: Duration::from_millis(0),
+            slow_callback_id: 0,
+            on_slow_callback: None
         }))
     };
+    register_local_queue(core.loop_id, &core.local_queue);
     let u = exec(&core, t, f);
     (core, u)
 }
 
-fn loop_core(w: Core)
-{
-    let mut events = mio::Events::with_capacity(1024);
-    let mut calls: Vec<CallbackEv> = Vec::new();
-    let mut callback_by_id: HashMap<i32, CallbackImpl> = HashMap::new();
-    loop {
-        debug!("Dispatching [{}] events", calls.len());
-        for ev in calls.drain(..) {
-            match ev {
-                CallbackEv::Req(c) => {
-                    let mut cbi = callback_by_id.get_mut(&c.canary.callback_id).unwrap();
-                    (cbi.dispatch)(&mut cbi, c.x);
-                }
-            }
+fn forget_callback(w: &Core, callback_by_id: &mut HashMap<i32, CallbackImpl>, id: i32) {
+    w.wp.borrow_mut().callback_info.remove(&id);
+    callback_by_id.remove(&id);
+}
+
+fn dispatch(w: &Core, callback_by_id: &mut HashMap<i32, CallbackImpl>, id: i32, x: Box<dyn Any>) {
+    let t0 = if w.wp.borrow().on_slow_callback.is_some() { Some(Instant::now()) } else { None };
+    let once = match callback_by_id.get_mut(&id) {
+        Some(cbi) => {
+            let _cx = async_hooks::enter(&cbi.async_cx);
+            (cbi.dispatch)(cbi, x);
+            cbi.once
         }
-        if !_get_events(&w, &mut calls, &mut events, &mut callback_by_id) { break; }
+        None => { warn!("Call to callback [{}] which no longer exists", id); return; }
+    };
+    if once { forget_callback(w, callback_by_id, id); }
+    if let Some(t0) = t0 {
+        let d = t0.elapsed();
+        if d < w.wp.borrow().slow_callback { return; }
+        // Taken out while it runs so that it can use the loop
+        let (id, h) = {
+            let mut wp = w.wp.borrow_mut();
+            (wp.slow_callback_id, wp.on_slow_callback.take())
+        };
+        if let Some(mut h) = h {
+            h(d);
+            let mut wp = w.wp.borrow_mut();
+            if wp.slow_callback_id == id { wp.on_slow_callback = Some(h); }
+        }
+    }
+}
+
+// Whatever is left can never be called, drop it now rather than leak it in a cycle through
+// the scopes it refers to.
+fn teardown(w: &Core) {
+    unregister_local_queue(w.loop_id);
+    let cbs = w.runner.borrow_mut().as_mut().map(|r| mem::take(&mut r.callback_by_id));
+    drop(cbs);
+    w.wp.borrow_mut().callback_info.clear();
+    let q = mem::take(&mut *w.local_queue.borrow_mut());
+    drop(q);
+    let hooks = mem::take(&mut w.wp.borrow_mut().on_before_exit);
+    drop(hooks);
+    let hook = w.wp.borrow_mut().on_slow_callback.take();
+    drop(hook);
+}
+
+pub(crate) fn loop_core(w: Core)
+{
+    // Stopped with things left to do
+    if w.run(RunMode::Default) {
+        exit_hooks(&w);
+        teardown(&w);
     }
-}
\ No newline at end of file
+}
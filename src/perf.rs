@@ -0,0 +1,135 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::{ Duration, Instant };
+
+use callback::Callback;
+use node::{ Loop, Core };
+use super::Token;
+
+/// Records durations with about 0.1% precision, like Node's RecordableHistogram.
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    // Nanoseconds, rounded to the 10 most significant bits
+    buckets: BTreeMap<u64, u64>,
+    count: u64,
+    sum: f64,
+    sum_sq: f64,
+    min: u64,
+    max: u64
+}
+impl Histogram {
+    pub fn new() -> Histogram { Histogram::default() }
+    pub fn record(&mut self, d: Duration) {
+        let ns = d.as_nanos().min(u64::MAX as u128) as u64;
+        let shift = (64 - ns.leading_zeros()).saturating_sub(10);
+        *self.buckets.entry((ns >> shift) << shift).or_insert(0) += 1;
+        if self.count == 0 || ns < self.min { self.min = ns; }
+        if ns > self.max { self.max = ns; }
+        self.count += 1;
+        self.sum += ns as f64;
+        self.sum_sq += ns as f64 * ns as f64;
+    }
+    pub fn count(&self) -> u64 { self.count }
+    pub fn min(&self) -> Duration { Duration::from_nanos(self.min) }
+    pub fn max(&self) -> Duration { Duration::from_nanos(self.max) }
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 { return Duration::from_nanos(0); }
+        Duration::from_nanos((self.sum / self.count as f64) as u64)
+    }
+    pub fn stddev(&self) -> Duration {
+        if self.count == 0 { return Duration::from_nanos(0); }
+        let mean = self.sum / self.count as f64;
+        let var = (self.sum_sq / self.count as f64 - mean * mean).max(0.0);
+        Duration::from_nanos(var.sqrt() as u64)
+    }
+    /// The value which p percent of the recorded values are at or below
+    pub fn percentile(&self, p: f64) -> Duration {
+        let want = ((p / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (ns, n) in &self.buckets {
+            seen += n;
+            if seen >= want { return Duration::from_nanos(*ns); }
+        }
+        self.max()
+    }
+    pub fn reset(&mut self) { *self = Histogram::default(); }
+}
+
+/// Samples how late the loop is running, see monitor_event_loop_delay(). Sampling stops when
+/// this is dropped.
+pub struct EventLoopDelay {
+    h: Rc<RefCell<Histogram>>,
+    core: Core,
+    token: Token
+}
+impl EventLoopDelay {
+    pub fn histogram(&self) -> Histogram { self.h.borrow().clone() }
+    pub fn reset(&self) { self.h.borrow_mut().reset() }
+}
+impl Drop for EventLoopDelay {
+    fn drop(&mut self) { let _ = self.core.deregister_event(&self.token); }
+}
+
+/// Every resolution ms, record how much later than that the loop got round to it. The timer
+/// doesn't keep the loop alive.
+pub fn monitor_event_loop_delay<L:Loop<L>>(l:&L, resolution: u64) -> EventLoopDelay {
+    let h = Rc::new(RefCell::new(Histogram::new()));
+    let expect = Duration::from_millis(resolution);
+    let cb = Callback::new_unref(l.core(), (h.clone(), Instant::now()), move |s, _: Token|{
+        let now = Instant::now();
+        s.0.borrow_mut().record(now.duration_since(s.1).saturating_sub(expect));
+        s.1 = now;
+    });
+    let token = l.core().set_timeout(cb, resolution, true);
+    l.core().unref_timer(&token);
+    EventLoopDelay { h, core: l.core().clone(), token }
+}
+
+/// How the loop's time has been split between waiting for events and running callbacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elu {
+    pub idle: Duration,
+    pub active: Duration
+}
+impl Elu {
+    /// The share of the time which was spent running, from 0 to 1
+    pub fn utilization(&self) -> f64 {
+        let total = (self.idle + self.active).as_secs_f64();
+        if total == 0.0 { 0.0 } else { self.active.as_secs_f64() / total }
+    }
+    /// The split since an earlier measurement
+    pub fn since(&self, prev: &Elu) -> Elu {
+        Elu {
+            idle: self.idle.saturating_sub(prev.idle),
+            active: self.active.saturating_sub(prev.active)
+        }
+    }
+}
+
+pub fn event_loop_utilization<L:Loop<L>>(l:&L) -> Elu {
+    let (total, idle) = l.core().loop_time();
+    Elu { idle, active: total.saturating_sub(idle) }
+}
+
+/// The hook set by on_slow_callback(), it is removed when this is dropped.
+pub struct SlowCallbackHook {
+    core: Core,
+    id: usize
+}
+impl Drop for SlowCallbackHook {
+    fn drop(&mut self) { self.core.clear_slow_callback_hook(self.id); }
+}
+
+/// Call f with the duration of every callback which runs for threshold ms or more, until the
+/// returned hook is dropped or l is gone. There is one hook per loop and setting another
+/// replaces it.
+pub fn on_slow_callback<L,F>(l:&L, threshold: u64, mut f:F) -> SlowCallbackHook where
+    L: Loop<L>,
+    F: 'static + FnMut(&mut L, Duration)
+{
+    let weak = Rc::downgrade(&l.as_rc());
+    let id = l.core().set_slow_callback_hook(Duration::from_millis(threshold),
+        Box::new(move |d|{ if let Some(rc) = weak.upgrade() { f(&mut *rc.borrow_mut(), d) } }));
+    SlowCallbackHook { core: l.core().clone(), id }
+}
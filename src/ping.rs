@@ -0,0 +1,179 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::process;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+use super::Token;
+
+use callback::Callback;
+use dgram::{ self, Af, AddrLike, Message, Sock };
+use node::{ Loop, Core };
+use time;
+
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REPLY_V4: u8 = 0;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+
+static NEXT_IDENT: AtomicUsize = AtomicUsize::new(0);
+
+struct Pending {
+    sent: Instant,
+    timeout: Token,
+    cb: Callback<io::Result<Duration>>
+}
+
+struct PingerPvt {
+    af: Af,
+    // Unprivileged ICMP sockets have their identifier rewritten by the kernel and only receive
+    // their own replies, raw sockets receive everything so we filter on identifier.
+    raw: bool,
+    ident: u16,
+    next_seq: u16,
+    pending: HashMap<u16, Pending>,
+    core: Core
+}
+
+pub struct Pinger {
+    sock: Sock,
+    pvt: Rc<RefCell<PingerPvt>>
+}
+
+fn checksum(buf: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in buf.chunks(2) {
+        let word = if chunk.len() == 2 { (chunk[0] as u32) << 8 | chunk[1] as u32 }
+            else { (chunk[0] as u32) << 8 };
+        sum += word;
+    }
+    while sum >> 16 != 0 { sum = (sum & 0xffff) + (sum >> 16); }
+    !(sum as u16)
+}
+
+fn on_reply(pvt_: &Rc<RefCell<PingerPvt>>, msg: Message) {
+    let mut pvt = pvt_.borrow_mut();
+    let mut icmp = &msg.buf[..];
+    if pvt.raw && pvt.af == Af::Inet {
+        // Raw IPv4 sockets deliver the IP header as well
+        if icmp.is_empty() { return; }
+        let ihl = ((icmp[0] & 0x0f) as usize) * 4;
+        if icmp.len() < ihl { return; }
+        icmp = &icmp[ihl..];
+    }
+    if icmp.len() < 8 { return; }
+    let reply = if pvt.af == Af::Inet { ECHO_REPLY_V4 } else { ECHO_REPLY_V6 };
+    if icmp[0] != reply { return; }
+    let ident = (icmp[4] as u16) << 8 | icmp[5] as u16;
+    if pvt.raw && ident != pvt.ident { return; }
+    let seq = (icmp[6] as u16) << 8 | icmp[7] as u16;
+    let p = match pvt.pending.remove(&seq) {
+        Some(p) => p,
+        None => { debug!("Unexpected echo reply seq {} from {:?}", seq, msg.sa); return; }
+    };
+    let _ = pvt.core.deregister_event(&p.timeout);
+    p.cb.call(Ok(p.sent.elapsed()));
+}
+
+fn mk_socket(afs: &'static str, addr: &'static str) -> io::Result<(Sock, bool)> {
+    match dgram::create_socket(afs)?.bind(addr) {
+        Ok(s) => Ok((s, false)),
+        Err(e) => {
+            if e.kind() != ErrorKind::PermissionDenied { return Err(e); }
+            let raw = if afs == "icmp4" { "raw4" } else { "raw6" };
+            debug!("Unprivileged {} denied, trying {}", afs, raw);
+            Ok((dgram::create_socket(raw)?.bind(addr)?, true))
+        }
+    }
+}
+
+/// Create a pinger, afs is icmp4 or icmp6. An unprivileged ICMP socket is used if the system
+/// allows it, otherwise a raw socket.
+pub fn create_pinger<L:Loop<L>>(l:&L, afs: &'static str) -> io::Result<Pinger> {
+    let (af, addr) = match afs {
+        "icmp4" => (Af::Inet, "0.0.0.0"),
+        "icmp6" => (Af::Inet6, "::"),
+        _ => { return Err(io::Error::new(ErrorKind::InvalidInput, "expecting icmp4 or icmp6")); }
+    };
+    let (sock, raw) = mk_socket(afs, addr)?;
+    let ident = (process::id() as usize + NEXT_IDENT.fetch_add(1, Ordering::Relaxed)) as u16;
+    let pvt = Rc::new(RefCell::new(PingerPvt {
+        af,
+        raw,
+        ident,
+        next_seq: 0,
+        pending: HashMap::new(),
+        core: l.core().clone()
+    }));
+    let pvt2 = pvt.clone();
+    sock.on_message(l, move |_,msg|{ on_reply(&pvt2, msg); });
+    Ok(Pinger { sock, pvt })
+}
+
+impl Pinger {
+    /// Send an echo request, the callback is called with the round trip time or with TimedOut
+    /// if there is no reply within timeout_ms. Returns the sequence number.
+    pub fn ping<L,F,A>(&self, l:&L, a:A, timeout_ms: u64, f:F) -> u16 where
+        L: Loop<L>,
+        F: 'static + FnOnce(&mut L, io::Result<Duration>),
+        A: AddrLike
+    {
+        let mut pvt = self.pvt.borrow_mut();
+        let seq = pvt.next_seq;
+        pvt.next_seq = pvt.next_seq.wrapping_add(1);
+
+        let mut pkt = vec![0u8; 16];
+        pkt[0] = if pvt.af == Af::Inet { ECHO_REQUEST_V4 } else { ECHO_REQUEST_V6 };
+        pkt[4] = (pvt.ident >> 8) as u8;
+        pkt[5] = pvt.ident as u8;
+        pkt[6] = (seq >> 8) as u8;
+        pkt[7] = seq as u8;
+        pkt[8..].copy_from_slice(b"noders!!");
+        // The kernel fills in the checksum for ICMPv6
+        if pvt.af == Af::Inet {
+            let csum = checksum(&pkt);
+            pkt[2] = (csum >> 8) as u8;
+            pkt[3] = csum as u8;
+        }
+
+        let pvt_ = self.pvt.clone();
+        let timeout = time::set_timeout(l, move |_,_|{
+            let p = pvt_.borrow_mut().pending.remove(&seq);
+            if let Some(p) = p {
+                p.cb.call(Err(io::Error::new(ErrorKind::TimedOut, "ping timed out")));
+            }
+        }, timeout_ms);
+        pvt.pending.insert(seq, Pending { sent: Instant::now(), timeout, cb: l.cb_once(f) });
+        drop(pvt);
+
+        let pvt_ = self.pvt.clone();
+        self.sock.send_to(l, pkt, a, move |_,res|{
+            if let Err(e) = res {
+                let p = pvt_.borrow_mut().pending.remove(&seq);
+                if let Some(p) = p {
+                    p.cb.call(Err(e));
+                    let _ = pvt_.borrow().core.deregister_event(&p.timeout);
+                }
+            }
+        });
+        seq
+    }
+
+    /// Whether the pinger fell back to a raw socket
+    pub fn is_raw(&self) -> bool { self.pvt.borrow().raw }
+
+    pub fn sock(&self) -> &Sock { &self.sock }
+
+    /// Close the socket, outstanding pings are failed with NotConnected.
+    pub fn close(&self) {
+        self.sock.close();
+        let mut pvt = self.pvt.borrow_mut();
+        let pending: Vec<Pending> = pvt.pending.drain().map(|(_, p)| p).collect();
+        for p in pending {
+            let _ = pvt.core.deregister_event(&p.timeout);
+            p.cb.call(Err(io::Error::new(ErrorKind::NotConnected, "pinger closed")));
+        }
+    }
+}
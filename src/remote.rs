@@ -0,0 +1,36 @@
+use std::sync::{ Arc, Mutex };
+
+use callback::Callback;
+use node::Loop;
+use time;
+use super::Token;
+
+type Job<A> = Box<dyn FnOnce(&mut A) + Send>;
+
+/// A handle which lets any thread run closures on a loop, with access to the loop's state.
+/// Like a callback it keeps the loop alive until every clone has been dropped.
+pub struct LoopRemote<A> where A: 'static {
+    cb: Arc<Mutex<Callback<Job<A>>>>
+}
+impl<A> Clone for LoopRemote<A> where A: 'static {
+    fn clone(&self) -> Self { LoopRemote { cb: self.cb.clone() } }
+}
+impl<A> LoopRemote<A> where A: 'static + Loop<A> {
+    pub fn new<L:Loop<A>>(l:&L) -> LoopRemote<A> {
+        let cb = l.cb(|l, f: Job<A>|{ f(l) });
+        LoopRemote { cb: Arc::new(Mutex::new(cb)) }
+    }
+    /// Run f on the loop's thread
+    pub fn post<F>(&self, f:F) where F: 'static + FnOnce(&mut A) + Send {
+        match self.cb.lock() {
+            Ok(cb) => cb.call(Box::new(f)),
+            Err(e) => { warn!("LoopRemote lock poisoned {:?}", &e); }
+        }
+    }
+    /// Like time::set_timeout() on the loop's thread
+    pub fn set_timeout_remote<F>(&self, millis: u64, f:F) where
+        F: 'static + FnOnce(&mut A, Token) + Send
+    {
+        self.post(move |l|{ time::set_timeout(&*l, f, millis); });
+    }
+}
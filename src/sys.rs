@@ -0,0 +1,253 @@
+// Socket calls which mio does not provide: ancillary data (control messages) through
+// recvmsg()/sendmsg() and creating ICMP and raw sockets.
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use libc;
+    use std::io;
+    use std::mem;
+    use std::ptr;
+    use std::net::{ SocketAddr, SocketAddrV4, SocketAddrV6, IpAddr, Ipv4Addr, Ipv6Addr };
+    use std::time::{ Duration, UNIX_EPOCH };
+    use std::os::unix::io::{ AsRawFd, FromRawFd };
+    use mio::net::UdpSocket;
+
+    use dgram::{ Af, Proto, MsgMeta };
+
+    fn setsockopt_int(fd: libc::c_int, level: libc::c_int, opt: libc::c_int) -> io::Result<()> {
+        let one: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(fd, level, opt, &one as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if ret < 0 { return Err(io::Error::last_os_error()); }
+        Ok(())
+    }
+
+    pub fn enable_meta(s: &UdpSocket, af: Af) -> io::Result<()> {
+        let fd = s.as_raw_fd();
+        match af {
+            Af::Inet => {
+                setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_PKTINFO)?;
+                setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_RECVTTL)?;
+                setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_RECVTOS)?;
+            }
+            Af::Inet6 => {
+                setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)?;
+                setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT)?;
+                setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS)?;
+            }
+        }
+        setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS)
+    }
+
+    fn to_sockaddr(sa: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut ss: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match *sa {
+            SocketAddr::V4(ref a) => {
+                let sin = unsafe { &mut *(&mut ss as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = a.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(ref a) => {
+                let sin6 = unsafe { &mut *(&mut ss as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = a.port().to_be();
+                sin6.sin6_addr.s6_addr = a.ip().octets();
+                sin6.sin6_flowinfo = a.flowinfo();
+                sin6.sin6_scope_id = a.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (ss, len as libc::socklen_t)
+    }
+
+    pub fn socket(af: Af, proto: Proto, addr: &SocketAddr, exclusive: bool) -> io::Result<UdpSocket> {
+        let (domain, icmp) = match af {
+            Af::Inet => (libc::AF_INET, libc::IPPROTO_ICMP),
+            Af::Inet6 => (libc::AF_INET6, libc::IPPROTO_ICMPV6)
+        };
+        let (ty, protocol) = match proto {
+            Proto::Udp => (libc::SOCK_DGRAM, 0),
+            Proto::Icmp => (libc::SOCK_DGRAM, icmp),
+            Proto::Raw => (libc::SOCK_RAW, icmp)
+        };
+        let fd = unsafe { libc::socket(domain, ty | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, protocol) };
+        if fd < 0 { return Err(io::Error::last_os_error()); }
+        // Owned from here on so that the fd is closed if bind fails
+        let s = unsafe { ::std::net::UdpSocket::from_raw_fd(fd) };
+        if !exclusive {
+            setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR)?;
+            setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT)?;
+        }
+        let (ss, len) = to_sockaddr(addr);
+        if unsafe { libc::bind(fd, &ss as *const _ as *const libc::sockaddr, len) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        UdpSocket::from_socket(s)
+    }
+
+    fn from_sockaddr(ss: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match ss.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ss as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ss as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown address family"))
+        }
+    }
+
+    // Enough room for pktinfo, ttl, tos and a timestamp with some slack, u64 for alignment.
+    type CmsgBuf = [u64; 32];
+
+    pub fn recv_from(s: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, MsgMeta)> {
+        let mut ss: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut cbuf: CmsgBuf = [0; 32];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len()
+        };
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name = &mut ss as *mut _ as *mut libc::c_void;
+        hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = mem::size_of::<CmsgBuf>() as _;
+
+        let count = unsafe { libc::recvmsg(s.as_raw_fd(), &mut hdr, 0) };
+        if count < 0 { return Err(io::Error::last_os_error()); }
+        let sa = from_sockaddr(&ss)?;
+
+        // Set if the kernel dropped control messages which didn't fit in cbuf
+        let mut meta = MsgMeta { truncated: hdr.msg_flags & libc::MSG_CTRUNC != 0,
+            ..MsgMeta::default() };
+        let mut cm = unsafe { libc::CMSG_FIRSTHDR(&hdr) };
+        while !cm.is_null() {
+            let (level, ty, data) = unsafe { ((*cm).cmsg_level, (*cm).cmsg_type, libc::CMSG_DATA(cm)) };
+            unsafe {
+                match (level, ty) {
+                    (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                        let pi: libc::in_pktinfo = ptr::read_unaligned(data as *const _);
+                        meta.local = Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(pi.ipi_addr.s_addr))));
+                        meta.ifindex = pi.ipi_ifindex as u32;
+                    }
+                    (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                        let pi: libc::in6_pktinfo = ptr::read_unaligned(data as *const _);
+                        meta.local = Some(IpAddr::V6(Ipv6Addr::from(pi.ipi6_addr.s6_addr)));
+                        meta.ifindex = pi.ipi6_ifindex;
+                    }
+                    (libc::IPPROTO_IP, libc::IP_TTL) => {
+                        let ttl: libc::c_int = ptr::read_unaligned(data as *const _);
+                        meta.ttl = Some(ttl as u8);
+                    }
+                    (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) => {
+                        let hl: libc::c_int = ptr::read_unaligned(data as *const _);
+                        meta.ttl = Some(hl as u8);
+                    }
+                    (libc::IPPROTO_IP, libc::IP_TOS) => {
+                        let tos: u8 = ptr::read_unaligned(data as *const _);
+                        meta.ecn = Some(tos & 0x03);
+                    }
+                    (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                        let tc: libc::c_int = ptr::read_unaligned(data as *const _);
+                        meta.ecn = Some((tc & 0x03) as u8);
+                    }
+                    (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                        let ts: libc::timespec = ptr::read_unaligned(data as *const _);
+                        meta.timestamp = Some(UNIX_EPOCH +
+                            Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32));
+                    }
+                    _ => { debug!("Unexpected cmsg {} {}", level, ty); }
+                }
+                cm = libc::CMSG_NXTHDR(&hdr, cm);
+            }
+        }
+        Ok((count as usize, sa, meta))
+    }
+
+    pub fn send_from(s: &UdpSocket, buf: &[u8], to: &SocketAddr, from: &IpAddr, ifindex: u32)
+        -> io::Result<usize>
+    {
+        let (mut ss, sslen) = to_sockaddr(to);
+        let mut cbuf: CmsgBuf = [0; 32];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len()
+        };
+        let (level, ty, size) = match *from {
+            IpAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_PKTINFO, mem::size_of::<libc::in_pktinfo>()),
+            IpAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, mem::size_of::<libc::in6_pktinfo>())
+        };
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name = &mut ss as *mut _ as *mut libc::c_void;
+        hdr.msg_namelen = sslen;
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = unsafe { libc::CMSG_SPACE(size as libc::c_uint) } as _;
+        unsafe {
+            let cm = libc::CMSG_FIRSTHDR(&hdr);
+            (*cm).cmsg_level = level;
+            (*cm).cmsg_type = ty;
+            (*cm).cmsg_len = libc::CMSG_LEN(size as libc::c_uint) as _;
+            let data = libc::CMSG_DATA(cm);
+            match *from {
+                IpAddr::V4(ip) => {
+                    let mut pi: libc::in_pktinfo = mem::zeroed();
+                    pi.ipi_ifindex = ifindex as libc::c_int;
+                    pi.ipi_spec_dst.s_addr = u32::from(ip).to_be();
+                    ptr::write_unaligned(data as *mut libc::in_pktinfo, pi);
+                }
+                IpAddr::V6(ip) => {
+                    let mut pi: libc::in6_pktinfo = mem::zeroed();
+                    pi.ipi6_ifindex = ifindex;
+                    pi.ipi6_addr.s6_addr = ip.octets();
+                    ptr::write_unaligned(data as *mut libc::in6_pktinfo, pi);
+                }
+            }
+        }
+        let count = unsafe { libc::sendmsg(s.as_raw_fd(), &hdr, 0) };
+        if count < 0 { return Err(io::Error::last_os_error()); }
+        Ok(count as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+    use std::net::{ SocketAddr, IpAddr };
+    use mio::net::UdpSocket;
+
+    use dgram::{ Af, Proto, MsgMeta };
+
+    fn unsupported<T>() -> io::Result<T> {
+        Err(io::Error::new(io::ErrorKind::Other, "not supported on this platform"))
+    }
+    pub fn socket(_af: Af, proto: Proto, addr: &SocketAddr, exclusive: bool) -> io::Result<UdpSocket> {
+        match proto {
+            Proto::Udp if exclusive => UdpSocket::bind(addr),
+            _ => unsupported()
+        }
+    }
+    pub fn enable_meta(_s: &UdpSocket, _af: Af) -> io::Result<()> { unsupported() }
+    pub fn recv_from(_s: &UdpSocket, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr, MsgMeta)> {
+        unsupported()
+    }
+    pub fn send_from(_s: &UdpSocket, _buf: &[u8], _to: &SocketAddr, _from: &IpAddr, _ifindex: u32)
+        -> io::Result<usize>
+    {
+        unsupported()
+    }
+}
+
+pub use self::imp::{ socket, enable_meta, recv_from, send_from };
@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::panic::{ self, AssertUnwindSafe };
+use std::sync::{ Arc, Mutex, Condvar };
+use std::thread;
+
+use node::Loop;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    shutdown: bool
+}
+
+/// A fixed set of threads which run blocking jobs for a loop, see queue_work().
+pub struct ThreadPool {
+    q: Arc<(Mutex<Queue>, Condvar)>,
+    threads: Vec<thread::JoinHandle<()>>
+}
+
+fn run_jobs(q: Arc<(Mutex<Queue>, Condvar)>) {
+    loop {
+        let job = {
+            let mut queue = q.0.lock().unwrap();
+            loop {
+                if queue.shutdown { return; }
+                if let Some(job) = queue.jobs.pop_front() { break job; }
+                queue = q.1.wait(queue).unwrap();
+            }
+        };
+        // A job which panics loses its result but not the thread
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("threadpool job panicked");
+        }
+    }
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        let q = Arc::new((Mutex::new(Queue { jobs: VecDeque::new(), shutdown: false }),
+            Condvar::new()));
+        let threads = (0..size.max(1)).map(|_| {
+            let q = q.clone();
+            thread::spawn(move || run_jobs(q))
+        }).collect();
+        ThreadPool { q, threads }
+    }
+    pub fn size(&self) -> usize { self.threads.len() }
+    fn queue(&self, job: Job) {
+        self.q.0.lock().unwrap().jobs.push_back(job);
+        self.q.1.notify_one();
+    }
+}
+impl Drop for ThreadPool {
+    // Only the jobs which are already running are waited for, the rest are dropped
+    fn drop(&mut self) {
+        let jobs = {
+            let mut queue = self.q.0.lock().unwrap();
+            queue.shutdown = true;
+            mem::take(&mut queue.jobs)
+        };
+        drop(jobs);
+        self.q.1.notify_all();
+        for t in self.threads.drain(..) { let _ = t.join(); }
+    }
+}
+
+/// Run job on the loop's thread pool and call f on the loop with its result, the pending
+/// callback keeps the loop alive meanwhile. If the job panics f is never called.
+pub fn queue_work<L,J,R,F>(l:&L, job:J, f:F) where
+    L: Loop<L>,
+    J: 'static + FnOnce() -> R + Send,
+    R: 'static + Send,
+    F: 'static + FnOnce(&mut L, R)
+{
+    let cb = l.cb_once(f);
+    l.core().threadpool().queue(Box::new(move || { cb.call_once(job()); }));
+}
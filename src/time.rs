@@ -1,18 +1,78 @@
-use node::Loop;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+use std::io;
+use std::rc::Rc;
+use std::cell::Cell;
+use abort::{ AbortSignal, abortable };
+use node::{ Loop, Core };
+use callback::Callback;
+use future::{ promise, Promise };
 use super::Token;
 
+// The timer belongs to the scope and is cancelled if the scope is closed
+fn own<L:Loop<L>>(l:&L, t: Token) -> Token {
+    l.core().own(t, l.resources());
+    t
+}
+
 pub fn set_timeout<L,F>(l:&L, cb:F, millis: u64) -> Token where
     L: Loop<L>,
-    F: 'static + Fn(&mut L, Token)
+    F: 'static + FnOnce(&mut L, Token)
+{
+    let t = l.core().set_timeout(l.cb_once(|l: &mut L, t|{
+        l.forget_dispose(t);
+        cb(l, t)
+    }), millis, false);
+    own(l, t)
+}
+/// Like set_timeout() but if the signal fires first the timer is cleared and cb gets an
+/// Aborted error.
+pub fn set_timeout_signal<L,F>(l:&L, signal: &AbortSignal, cb:F, millis: u64) -> Token where
+    L: Loop<L>,
+    F: 'static + FnOnce(&mut L, io::Result<Token>)
 {
-    l.core().set_timeout(l.cb(cb), millis, false)
+    let f = abortable(l, signal, cb);
+    let (sig, id) = (signal.clone(), Rc::new(Cell::new(0)));
+    let id_ = id.clone();
+    let t = set_timeout(l, move |l, t|{
+        // Once it has fired there's nothing to clear
+        sig.forget(id_.get());
+        f(l, Ok(t))
+    }, millis);
+    let c = l.core().clone();
+    id.set(signal.on_abort(move ||{ let _ = c.deregister_event(&t); }));
+    t
 }
 pub fn set_interval<L,F>(l:&L, cb:F, millis: u64) -> Token where
     L: Loop<L>,
-    F: 'static + Fn(&mut L, Token)
+    F: 'static + FnMut(&mut L, Token)
 {
-    l.core().set_timeout(l.cb(cb), millis, true)
+    let t = l.core().set_timeout(l.cb(cb), millis, true);
+    own(l, t)
 }
 pub fn clear_timeout<L:Loop<L>>(l:&L, t: Token) -> bool {
-    match l.core().deregister_event(&t) { Ok(r) => r, Err(_) => false }
-}
\ No newline at end of file
+    l.core().deregister_event(&t).unwrap_or(false)
+}
+/// A future which completes after a delay, dropping it cancels the timer.
+pub struct Sleep {
+    p: Promise<Token>,
+    core: Core,
+    token: Token
+}
+impl Future for Sleep {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.p).poll(cx).map(|_| ())
+    }
+}
+impl Drop for Sleep {
+    fn drop(&mut self) { let _ = self.core.deregister_event(&self.token); }
+}
+
+pub fn sleep<L:Loop<L>>(l:&L, millis: u64) -> Sleep {
+    let (p, r) = promise();
+    let cb = Callback::new_once(l.core(), (), move |_,t|{ r.resolve(t) });
+    let token = l.core().set_timeout(cb, millis, false);
+    Sleep { p, core: l.core().clone(), token }
+}
@@ -0,0 +1,174 @@
+use std::rc::Rc;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::panic::{ self, AssertUnwindSafe };
+use std::sync::mpsc;
+use std::thread;
+use mio_extras::channel::Sender;
+
+use callback::{ Callback, CallbackEv };
+use node::{ Loop, Scope, new_core, loop_core };
+
+struct PortPvt<In> where In: Send + 'static {
+    on_message: Option<Callback<In>>,
+    // Messages which arrived before there was a handler
+    queue: VecDeque<In>,
+    on_exit: Vec<Callback<()>>,
+    exited: bool,
+    // What the worker panicked with
+    panic: Option<String>
+}
+impl<In> PortPvt<In> where In: Send + 'static {
+    fn new() -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(PortPvt {
+            on_message: None,
+            queue: VecDeque::new(),
+            on_exit: Vec::new(),
+            exited: false,
+            panic: None
+        }))
+    }
+}
+
+fn deliver<In:Send+'static>(pvt: &Rc<RefCell<PortPvt<In>>>, m: In) {
+    let mut pvt = pvt.borrow_mut();
+    match pvt.on_message.as_ref() {
+        Some(cb) => cb.call(m),
+        None => pvt.queue.push_back(m)
+    }
+}
+
+fn exited<In:Send+'static>(pvt: &Rc<RefCell<PortPvt<In>>>, panic: Option<String>) {
+    let mut pvt = pvt.borrow_mut();
+    pvt.exited = true;
+    pvt.panic = panic;
+    // Nothing more can arrive so stop keeping the loop alive for it
+    pvt.on_message = None;
+    for cb in pvt.on_exit.drain(..) { cb.call(()); }
+}
+
+/// One end of a channel between two loops, messages posted at one end are received by the
+/// on_message handler at the other. A port with a handler keeps its loop alive until close().
+pub struct MessagePort<In, Out> where In: Send + 'static, Out: Send + 'static {
+    tx: RefCell<Option<Callback<Out>>>,
+    pvt: Rc<RefCell<PortPvt<In>>>
+}
+impl<In, Out> MessagePort<In, Out> where In: Send + 'static, Out: Send + 'static {
+    pub fn post_message(&self, m: Out) {
+        if let Some(tx) = self.tx.borrow().as_ref() { tx.call(m); }
+    }
+    /// Set the handler for incoming messages, any which arrived before it was set are
+    /// delivered to it first.
+    pub fn on_message<L,F>(&self, l:&L, f:F) -> &Self where
+        L: Loop<L>,
+        F: 'static + FnMut(&mut L, In)
+    {
+        let mut pvt = self.pvt.borrow_mut();
+        if pvt.exited { return self; }
+        let cb = l.cb(f);
+        for m in pvt.queue.drain(..) { cb.call(m); }
+        pvt.on_message = Some(cb);
+        self
+    }
+    /// Stop receiving and drop messages posted after this.
+    pub fn close(&self) {
+        self.tx.borrow_mut().take();
+        let mut pvt = self.pvt.borrow_mut();
+        pvt.on_message = None;
+        pvt.queue.clear();
+    }
+}
+
+/// The parent's handle to a loop running on another thread.
+pub struct Worker<P, C> where P: Send + 'static, C: Send + 'static {
+    port: MessagePort<C, P>,
+    stop: Sender<CallbackEv>
+}
+impl<P, C> Worker<P, C> where P: Send + 'static, C: Send + 'static {
+    pub fn post_message(&self, m: P) -> &Self { self.port.post_message(m); self }
+    pub fn on_message<L,F>(&self, l:&L, f:F) -> &Self where
+        L: Loop<L>,
+        F: 'static + FnMut(&mut L, C)
+    {
+        self.port.on_message(l, f);
+        self
+    }
+    /// Called once the worker's loop has finished, messages it posted are delivered first. If
+    /// the worker panicked, f gets an error with the panic message.
+    pub fn on_exit<L,F>(&self, l:&L, f:F) -> &Self where
+        L: Loop<L>,
+        F: 'static + FnOnce(&mut L, io::Result<()>)
+    {
+        let pvt = self.port.pvt.clone();
+        let cb = l.cb_once(move |l,_|{
+            let panic = pvt.borrow().panic.clone();
+            f(l, match panic {
+                Some(m) => Err(io::Error::other(format!("worker panicked: {}", m))),
+                None => Ok(())
+            })
+        });
+        let mut pvt = self.port.pvt.borrow_mut();
+        if pvt.exited { cb.call(()); } else { pvt.on_exit.push(cb); }
+        self
+    }
+    /// Stop the worker's loop as soon as it has finished what it's doing now.
+    pub fn terminate(&self) {
+        self.port.tx.borrow_mut().take();
+        let _ = self.stop.send(CallbackEv::Stop);
+    }
+}
+
+fn panic_message(e: &(dyn Any + Send)) -> String {
+    match e.downcast_ref::<&str>() {
+        Some(s) => s.to_string(),
+        None => e.downcast_ref::<String>().cloned().unwrap_or_else(|| "unknown panic".into())
+    }
+}
+
+/// Start a loop on a new thread, f gets the worker's end of the message channel. The worker
+/// runs until its own loop has nothing left to do (a port with a message handler counts) or
+/// it is terminated, meanwhile it keeps the parent loop alive. Fails if the worker's loop
+/// can't be made.
+pub fn worker<L,T,P,C,F>(l:&L, t:T, f:F) -> io::Result<Worker<P,C>> where
+    L: Loop<L>,
+    F: 'static + Send + FnOnce(&mut Scope<T>, MessagePort<P,C>),
+    T: Send + 'static,
+    P: Send + 'static,
+    C: Send + 'static
+{
+    let pvt = PortPvt::new();
+    // The worker holding these doesn't keep the parent alive, the exit callback does.
+    let to_parent: Callback<C> = Callback::new_unref(l.core(), pvt.clone(), |pvt,m|{
+        deliver(pvt, m)
+    });
+    let exit: Callback<Option<String>> = Callback::new(l.core(), pvt.clone(), |pvt,panic|{
+        exited(pvt, panic)
+    });
+
+    let (tx, rx) = mpsc::channel();
+    let (tps, clock) = (l.core().threadpool_size(), l.core().clock());
+    thread::spawn(move || {
+        // The parent is told about a panic in f or in the worker's callbacks, rather than just
+        // losing the worker.
+        let res = panic::catch_unwind(AssertUnwindSafe(move || {
+            let (core, _) = new_core(t, tps, clock, None, move |s| {
+                let cpvt = PortPvt::new();
+                let to_child: Callback<P> = Callback::new_unref(s.core(), cpvt.clone(), |pvt,m|{
+                    deliver(pvt, m)
+                });
+                let _ = tx.send((to_child, s.core().callback_sender.clone()));
+                f(s, MessagePort { tx: RefCell::new(Some(to_parent)), pvt: cpvt })
+            });
+            loop_core(core);
+        }));
+        exit.call_once(res.err().map(|e| panic_message(&*e)));
+    });
+    match rx.recv() {
+        Ok((to_child, stop)) => {
+            Ok(Worker { port: MessagePort { tx: RefCell::new(Some(to_child)), pvt }, stop })
+        },
+        Err(_) => Err(io::Error::other("worker loop did not start"))
+    }
+}